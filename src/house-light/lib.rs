@@ -0,0 +1,185 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use log::{debug, info};
+use thiserror;
+
+const LED_DIR: &str = "/sys/class/leds/starboard::lights";
+const PWM_CHIP: &str = "/sys/class/pwm/pwmchip2";
+const PWM_CHANNEL: u32 = 1;
+const PWM_PERIOD: u32 = 500000;
+
+/// Brightness control of the house light, on a scale of 0 to 255 whatever the hardware range.
+pub trait HouseLight {
+    fn set(&mut self, brightness: u8) -> Result<(), Error>;
+
+    /// Brightness currently set, read back from the hardware.
+    fn get(&self) -> Result<u8, Error>;
+
+    fn describe(&self) -> String;
+}
+
+/// Find the house light, preferring the LED class device from the device tree
+/// and falling back to driving the PWM channel directly.
+pub fn detect() -> Result<Box<dyn HouseLight>, Error> {
+    if Path::new(LED_DIR).exists() {
+        info!("Changing house light LED with sysfs device tree.");
+        Ok(Box::new(LedClassLight::new(Path::new(LED_DIR))?))
+    } else {
+        info!("Sysfs device not found for house light. Defaulting to pwm method.");
+        Ok(Box::new(PwmLight::new(Path::new(PWM_CHIP), PWM_CHANNEL, PWM_PERIOD)?))
+    }
+}
+
+fn read_value(path: &Path) -> Result<String, Error> {
+    fs::read_to_string(path)
+        .map(|value| value.trim().to_string())
+        .map_err(|e| Error::ReadError {source: e, path: path.to_path_buf()})
+}
+
+fn read_number(path: &Path) -> Result<u32, Error> {
+    let value = read_value(path)?;
+    value.parse().map_err(|_| Error::ParseError {path: path.to_path_buf(), value})
+}
+
+fn write_value(path: &Path, value: &str) -> Result<(), Error> {
+    debug!("Writing {:?} to file {:?}", value, path);
+    fs::write(path, value).map_err(|e| Error::WriteError {source: e, path: path.to_path_buf()})
+}
+
+/// LED class device, e.g. /sys/class/leds/starboard::lights
+pub struct LedClassLight {
+    dir: PathBuf,
+    max_brightness: u32,
+}
+
+impl LedClassLight {
+    pub fn new(dir: &Path) -> Result<Self, Error> {
+        let max_brightness = read_number(&dir.join("max_brightness"))?;
+        Ok(LedClassLight {
+            dir: dir.to_path_buf(),
+            max_brightness,
+        })
+    }
+}
+
+impl HouseLight for LedClassLight {
+    fn set(&mut self, brightness: u8) -> Result<(), Error> {
+        let value = (brightness as f64 / 255.0 * self.max_brightness as f64).round() as u32;
+        write_value(&self.dir.join("brightness"), &value.to_string())
+    }
+
+    fn get(&self) -> Result<u8, Error> {
+        let value = read_number(&self.dir.join("brightness"))?;
+        Ok((value as f64 / self.max_brightness as f64 * 255.0).round().min(255.0) as u8)
+    }
+
+    fn describe(&self) -> String {
+        format!("LED {:?} with max brightness {}", self.dir, self.max_brightness)
+    }
+}
+
+/// PWM channel driving the light directly, e.g. /sys/class/pwm/pwmchip2/pwm1.
+/// Brightness is the fraction of each period the output is active, so the duty cycle
+/// written is inverted when the channel's polarity is inversed.
+pub struct PwmLight {
+    dir: PathBuf,
+    period: u32,
+    inversed: bool,
+}
+
+impl PwmLight {
+    /// Export and enable `channel` of `chip`. The period is set if the channel isn't
+    /// running yet, and polarity is left as configured, inversed by default.
+    pub fn new(chip: &Path, channel: u32, period: u32) -> Result<Self, Error> {
+        let dir = chip.join(format!("pwm{}", channel));
+        if !dir.exists() {
+            write_value(&chip.join("export"), &channel.to_string())?;
+        }
+        if read_value(&dir.join("enable"))? != "1" {
+            // period and polarity can only be changed while the channel is disabled
+            write_value(&dir.join("period"), &period.to_string())?;
+            write_value(&dir.join("polarity"), "inversed")?;
+            write_value(&dir.join("enable"), "1")?;
+        }
+        let period = read_number(&dir.join("period"))?;
+        let inversed = read_value(&dir.join("polarity"))? == "inversed";
+        Ok(PwmLight {
+            dir,
+            period,
+            inversed,
+        })
+    }
+}
+
+impl HouseLight for PwmLight {
+    fn set(&mut self, brightness: u8) -> Result<(), Error> {
+        let active = brightness as f64 / 255.0;
+        let duty = if self.inversed {1.0 - active} else {active};
+        let duty_cycle = (self.period as f64 * duty) as u32;
+        write_value(&self.dir.join("duty_cycle"), &duty_cycle.to_string())
+    }
+
+    fn get(&self) -> Result<u8, Error> {
+        let duty = read_number(&self.dir.join("duty_cycle"))? as f64 / self.period as f64;
+        let active = if self.inversed {1.0 - duty} else {duty};
+        Ok((active * 255.0).round().clamp(0.0, 255.0) as u8)
+    }
+
+    fn describe(&self) -> String {
+        format!("PWM {:?} with period {}ns{}", self.dir, self.period, if self.inversed {", inversed"} else {""})
+    }
+}
+
+/// Light that only remembers what it was set to, for trying out cycles without hardware.
+#[derive(Default)]
+pub struct FakeLight {
+    brightness: u8,
+    /// every value set, in order
+    pub history: Vec<u8>,
+}
+
+impl HouseLight for FakeLight {
+    fn set(&mut self, brightness: u8) -> Result<(), Error> {
+        debug!("Fake light set to {}", brightness);
+        self.brightness = brightness;
+        self.history.push(brightness);
+        Ok(())
+    }
+
+    fn get(&self) -> Result<u8, Error> {
+        Ok(self.brightness)
+    }
+
+    fn describe(&self) -> String {
+        String::from("fake light")
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed to read {path:?}")]
+    ReadError {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("Unexpected value '{value}' in {path:?}")]
+    ParseError {
+        path: PathBuf,
+        value: String,
+    },
+    #[error("Invalid line '{line}' in schedule {path:?}")]
+    ScheduleParse {
+        path: PathBuf,
+        line: String,
+    },
+    #[error("Invalid schedule {path:?}: {reason}")]
+    Schedule {
+        path: PathBuf,
+        reason: String,
+    },
+    #[error("Failed to write {path:?}")]
+    WriteError {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+}
\ No newline at end of file
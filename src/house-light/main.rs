@@ -1,13 +1,23 @@
-use std::fs;
+mod lib;
+mod schedule;
+mod transition;
 use std::io;
-use std::path::{PathBuf, Path};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 use chrono::{self, Timelike, prelude::*};
 use argh::{self,FromArgs};
 use simple_logger::SimpleLogger;
-use log::info;
+use log::{error, info, warn, LevelFilter};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use lib::{FakeLight, HouseLight};
+use schedule::Schedule;
+use transition::Fade;
 
 #[derive(FromArgs)]
-/// Manually control house light LED
+/// Control the house light LED. Without a subcommand, reads brightness levels from stdin
+#[argh(help_triggers("-h", "--help", "help"))]
 struct CliArgs {
     /// fake dawn value, defaults to 8AM
     #[argh(option, default = "default_dawn()")]
@@ -15,90 +25,492 @@ struct CliArgs {
     /// fake dusk value, defaults to 8PM
     #[argh(option, default = "default_dusk()")]
     dusk: f64,
+    /// brightness between dusk and dawn, defaults to 0
+    #[argh(option, default = "0")]
+    night_level: u8,
+    /// brightness at the peak of the day, defaults to 255
+    #[argh(option, default = "255")]
+    day_level: u8,
+    /// latitude in degrees north, for the 'ephemera' light cycle
+    #[argh(option)]
+    latitude: Option<f64>,
+    /// longitude in degrees east, for the 'ephemera' light cycle
+    #[argh(option)]
+    longitude: Option<f64>,
+    /// days added to today's date for the 'ephemera' light cycle, to simulate another season, defaults to 0
+    #[argh(option, default = "0")]
+    date_offset: i64,
+    /// follow the sun's actual altitude in 'ephemera' mode instead of an arc between sunrise and sunset
+    #[argh(switch)]
+    solar_altitude: bool,
+    /// timetable of dawn, dusk and peak brightness by date, for the 'schedule' light cycle
+    #[argh(option)]
+    schedule: Option<String>,
+    /// light cycle followed by auto and daemon, 'auto', 'ephemera' or 'schedule', defaults to auto
+    #[argh(option, default = "default_cycle()")]
+    cycle: String,
+    /// seconds to fade to a new brightness, defaults to 2
+    #[argh(option, default = "default_fade()")]
+    fade: f64,
+    /// gamma of the light's response, used to make fades look even, must be above 0, defaults to 2.2
+    #[argh(option, default = "default_gamma()")]
+    gamma: f64,
+    /// log brightness changes without touching the light
+    #[argh(switch)]
+    fake: bool,
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Set(SetArgs),
+    Get(GetArgs),
+    Auto(AutoArgs),
+    Off(OffArgs),
+    Status(StatusArgs),
+    Daemon(DaemonArgs),
+}
+
+#[derive(FromArgs)]
+/// Fade to a brightness level
+#[argh(subcommand, name = "set")]
+struct SetArgs {
+    #[argh(positional)]
+    /// brightness between 0 and 255
+    level: u8,
+}
+
+#[derive(FromArgs)]
+/// Print the current brightness level
+#[argh(subcommand, name = "get")]
+struct GetArgs {}
+
+#[derive(FromArgs)]
+/// Fade to the level the --cycle light cycle calls for now
+#[argh(subcommand, name = "auto")]
+struct AutoArgs {}
+
+#[derive(FromArgs)]
+/// Fade the light off
+#[argh(subcommand, name = "off")]
+struct OffArgs {}
+
+#[derive(FromArgs)]
+/// Print the light, its brightness, the --cycle level and the active schedule rule
+#[argh(subcommand, name = "status")]
+struct StatusArgs {}
+
+#[derive(FromArgs)]
+/// Follow the --cycle light cycle until SIGTERM
+#[argh(subcommand, name = "daemon")]
+struct DaemonArgs {
+    /// seconds between brightness updates, defaults to 60
+    #[argh(option, default = "default_interval()")]
+    interval: u64,
+    /// brightness left set when the daemon is stopped, defaults to 0
+    #[argh(option, default = "0")]
+    exit_level: u8,
 }
 
 fn default_dawn() -> f64 {8.0}
-fn default_dusk() -> f64 {8.0}
+fn default_dusk() -> f64 {20.0}
+fn default_cycle() -> String {String::from("auto")}
+fn default_interval() -> u64 {60}
+fn default_fade() -> f64 {2.0}
+fn default_gamma() -> f64 {2.2}
 
-fn main() {
-    SimpleLogger::new().init().unwrap();
+/// Exit code when the options or schedule don't make sense. argh exits with 1 for usage errors.
+const EXIT_CONFIG: i32 = 2;
+/// Exit code when the light can't be found, read or written.
+const EXIT_LIGHT: i32 = 3;
+
+#[derive(thiserror::Error, Debug)]
+enum Failure {
+    #[error("{reason}")]
+    Config {
+        reason: String,
+    },
+    #[error(transparent)]
+    Light {
+        #[from]
+        source: lib::Error,
+    },
+}
+
+impl Failure {
+    fn config(reason: &str) -> Self {
+        Failure::Config {reason: reason.to_string()}
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Config {..} => EXIT_CONFIG,
+            Failure::Light {..} => EXIT_LIGHT,
+        }
+    }
+
+    fn log(&self) {
+        error!("{}", self);
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            error!("  caused by: {}", cause);
+            source = cause.source();
+        }
+    }
+}
 
+fn main() {
     let args: CliArgs = argh::from_env();
-    let write_loc: String;
-    let write_mode: bool;
-    if !Path::new("/sys/class/leds/starboard::lights/brightness").exists() {
-        info!("Sysfs device not found for house light. Defaulting to pwm method.");
-        pwm_setup();
-        write_mode = true;
-        write_loc = String::from("/sys/class/pwm/pwmchip2/pwm1/duty_cycle");
+    // get and status print their answer on stdout, so keep the log quiet for them
+    let level = match args.command {
+        Some(Command::Get(_)) | Some(Command::Status(_)) => LevelFilter::Warn,
+        _ => LevelFilter::Trace,
+    };
+    SimpleLogger::new().with_level(level).init().unwrap();
+    if let Err(failure) = run(&args) {
+        failure.log();
+        std::process::exit(failure.exit_code())
+    }
+}
+
+fn run(args: &CliArgs) -> Result<(), Failure> {
+    // a gamma of 0 turns every fade into one jump at the end, a negative one runs it backwards
+    if args.gamma.is_nan() || args.gamma <= 0.0 {
+        return Err(Failure::config("--gamma must be above 0"))
+    }
+    let schedule = match &args.schedule {
+        Some(path) => Some(Schedule::load(Path::new(path))?),
+        None => None,
+    };
+    let mut light: Box<dyn HouseLight> = if args.fake {
+        Box::new(FakeLight::default())
     } else {
-        write_mode = false;
-        info!("Changing house light LED with sysfs device tree.");
-        write_loc = String::from("/sys/class/leds/starboard::lights/brightness");
+        lib::detect()?
+    };
+    info!("Using {}", light.describe());
+    if let Some(schedule) = &schedule {
+        info!("Schedule: {}", schedule.status(Local::now()));
     }
-    let path = fs::canonicalize(PathBuf::from(write_loc.clone())).unwrap();
+    let fade = Duration::from_secs_f64(args.fade.max(0.0));
 
-    loop {
-        let mut buffer = String::new();
-        info!("Input brightness level between 0 and 255, or 'auto' for artificial light cycle value");
-        io::stdin().read_line(&mut buffer).unwrap();
-        if buffer.trim() == String::from("auto") {
-            let altitude = calc_altitude(args.dawn, args.dusk);
-            let brightness = calc_brightness(altitude, 255);
-            if write_mode {
-                let duty_cycle: u32 = (500000.0 * (1.0 - (brightness as f64 / 255.0))) as u32;
-                info!("Writing {:?} to file {:?}", duty_cycle, write_loc);
-                fs::write(path.clone(), duty_cycle.to_string()).expect("Unable to write value to file");
-            } else {
-                info!("Writing {:?} to file {:?}", brightness, write_loc);
-                fs::write(path.clone(), brightness.to_string()).expect("Unable to write value to file");
+    match &args.command {
+        Some(Command::Set(set)) => fade_to(light.as_mut(), set.level, fade, args.gamma)?,
+        Some(Command::Get(_)) => println!("{}", light.get()?),
+        Some(Command::Auto(_)) => {
+            let brightness = cycle_brightness(&args.cycle, args, schedule.as_ref())?;
+            fade_to(light.as_mut(), brightness, fade, args.gamma)?
+        },
+        Some(Command::Off(_)) => fade_to(light.as_mut(), 0, fade, args.gamma)?,
+        Some(Command::Status(_)) => {
+            println!("light: {}", light.describe());
+            println!("brightness: {}", light.get()?);
+            match cycle_brightness(&args.cycle, args, schedule.as_ref()) {
+                Ok(brightness) => println!("{} cycle: {}", args.cycle, brightness),
+                Err(e) => println!("{} cycle: {}", args.cycle, e),
             }
-        } else {
-            let brightness: u8 = buffer.trim().parse().expect("Input not an integer.");
-            if write_mode {
-                let duty_cycle: u32 = (500000.0 * (1.0 - (brightness as f64 / 255.0))) as u32;
-                info!("Writing {:?} to file {:?}", duty_cycle, write_loc);
-                fs::write(path.clone(), duty_cycle.to_string()).expect("Unable to write value to file");
-            } else {
-                info!("Writing {:?} to file {:?}", brightness, write_loc);
-                fs::write(path.clone(), brightness.to_string()).expect("Unable to write value to file");
+            if let Some(schedule) = &schedule {
+                println!("schedule: {}", schedule.status(Local::now()));
             }
+        },
+        Some(Command::Daemon(daemon)) => run_daemon(args, daemon, light.as_mut(), schedule.as_ref())?,
+        None => interactive(args, light.as_mut(), schedule.as_ref()),
+    }
+    Ok(())
+}
+
+/// Read brightness levels and commands from stdin until it closes.
+fn interactive(args: &CliArgs, light: &mut dyn HouseLight, schedule: Option<&Schedule>) {
+    let fade = Duration::from_secs_f64(args.fade.max(0.0));
+    loop {
+        let mut buffer = String::new();
+        info!("Input brightness level between 0 and 255, 'auto' for artificial light cycle value, \
+               'ephemera' for the sun's cycle at --latitude and --longitude, 'schedule' for today's \
+               --schedule entry, or 'status' for the active schedule rule");
+        if io::stdin().read_line(&mut buffer).unwrap_or(0) == 0 {
+            return
+        }
+        let brightness: u8 = match buffer.trim() {
+            "status" => {
+                match schedule {
+                    Some(schedule) => info!("Schedule: {}", schedule.status(Local::now())),
+                    None => info!("No --schedule given"),
+                }
+                continue
+            },
+            cycle @ ("auto" | "ephemera" | "schedule") => match cycle_brightness(cycle, args, schedule) {
+                Ok(brightness) => brightness,
+                Err(failure) => {
+                    failure.log();
+                    continue
+                },
+            },
+            level => match level.parse() {
+                Ok(brightness) => brightness,
+                Err(_) => {
+                    warn!("'{}' is not a brightness between 0 and 255 or a known command", level);
+                    continue
+                }
+            },
+        };
+        if let Err(e) = fade_to(light, brightness, fade, args.gamma) {
+            Failure::from(e).log();
         }
     }
 }
 
-fn pwm_setup() {
-    // Set up the pwm device
-    // Brightness can be adjusted by writing to the duty_cycle to be a proportion of the period
-    let pwm_address: String = String::from("/sys/class/pwm/pwmchip2/pwm1/");
-    if !Path::new(&pwm_address).exists() {
-        let export_loc = String::from("/sys/class/pwm/pwmchip2/export");
-        fs::write(export_loc.clone(), "1").unwrap()
+/// Brightness the named light cycle calls for right now.
+fn cycle_brightness(cycle: &str, args: &CliArgs, schedule: Option<&Schedule>) -> Result<u8, Failure> {
+    match cycle {
+        "auto" => {
+            let altitude = calc_altitude(args.dawn, args.dusk);
+            Ok(calc_brightness(altitude, args.night_level, args.day_level))
+        },
+        "ephemera" => {
+            let (latitude, longitude) = match (args.latitude, args.longitude) {
+                (Some(latitude), Some(longitude)) => (latitude, longitude),
+                _ => return Err(Failure::config("The ephemera light cycle needs --latitude and --longitude")),
+            };
+            let altitude = calc_ephemera(latitude, longitude, args.date_offset, args.solar_altitude);
+            Ok(calc_brightness(altitude, args.night_level, args.day_level))
+        },
+        "schedule" => {
+            let schedule = schedule.ok_or_else(|| Failure::config("The schedule light cycle needs --schedule"))?;
+            let (rule, _) = schedule.rule_for(Local::now().date_naive());
+            let altitude = calc_altitude(rule.dawn, rule.dusk);
+            Ok(calc_brightness(altitude, args.night_level, rule.peak))
+        },
+        _ => Err(Failure::Config {
+            reason: format!("Unknown light cycle '{}', expected auto, ephemera or schedule", cycle)
+        }),
     }
-    let configs = vec!["period", "500000", "polarity", "inversed", "enable", "1"];
-    for pair in configs.chunks(2) {
-        let write_loc = format!("{}{}", pwm_address, pair[0]);
-        fs::write(write_loc.clone(), pair[1]).unwrap()
+}
+
+/// Fade from the light's current brightness to `target` over `duration`, blocking until done.
+fn fade_to(light: &mut dyn HouseLight, target: u8, duration: Duration, gamma: f64) -> Result<(), lib::Error> {
+    let from = light.get().unwrap_or(target);
+    info!("Fading from {} to {} over {:?}", from, target, duration);
+    let start = Instant::now();
+    for (at, level) in Fade::new(from, target, duration, gamma).steps() {
+        thread::sleep((start + at).saturating_duration_since(Instant::now()));
+        light.set(level)?;
     }
+    Ok(())
 }
 
+/// Sleep until `until`, returning false early if the daemon has been asked to stop.
+async fn sleep_or_stop(until: tokio::time::Instant, stop: &mut watch::Receiver<bool>) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep_until(until) => !*stop.borrow(),
+        _ = stop.changed() => false,
+    }
+}
+
+/// Follow the configured light cycle, updating brightness every `--interval` seconds
+/// until SIGTERM or Ctrl-C, then leave the light at `--exit-level`.
+/// Each update fades over the whole interval, so dawn and dusk are continuous.
+/// SIGUSR1 logs the active schedule rule and next transition.
+fn run_daemon(args: &CliArgs, daemon: &DaemonArgs, light: &mut dyn HouseLight, schedule: Option<&Schedule>)
+              -> Result<(), Failure> {
+    cycle_brightness(&args.cycle, args, schedule)?;
+    info!("Following the {} light cycle, updating every {}s", args.cycle, daemon.interval);
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let result: Result<(), Failure> = runtime.block_on(async {
+        let (stop_sender, mut stop) = watch::channel(false);
+        tokio::spawn(async move {
+            let mut terminate = signal(SignalKind::terminate()).unwrap();
+            tokio::select! {
+                _ = terminate.recv() => info!("Received SIGTERM, stopping"),
+                _ = tokio::signal::ctrl_c() => info!("Interrupted, stopping"),
+            }
+            stop_sender.send(true).unwrap();
+        });
+        if let Some(schedule) = schedule.cloned() {
+            tokio::spawn(async move {
+                let mut report = signal(SignalKind::user_defined1()).unwrap();
+                while report.recv().await.is_some() {
+                    info!("Schedule: {}", schedule.status(Local::now()));
+                }
+            });
+        }
+        let interval = Duration::from_secs(daemon.interval.max(1));
+        let mut current: Option<u8> = None;
+        'cycle: loop {
+            let next_update = tokio::time::Instant::now() + interval;
+            let brightness = cycle_brightness(&args.cycle, args, schedule)?;
+            if current != Some(brightness) {
+                let from = light.get().unwrap_or(brightness);
+                info!("Brightness {} -> {} at {}", from, brightness, Local::now());
+                let start = tokio::time::Instant::now();
+                for (at, level) in Fade::new(from, brightness, interval, args.gamma).steps() {
+                    if !sleep_or_stop(start + at, &mut stop).await {
+                        break 'cycle
+                    }
+                    light.set(level)?;
+                }
+                current = Some(brightness);
+            }
+            if !sleep_or_stop(next_update, &mut stop).await {
+                break
+            }
+        }
+        Ok(())
+    });
+    info!("Leaving house light at {}", daemon.exit_level);
+    light.set(daemon.exit_level)?;
+    result
+}
 
 fn calc_altitude(dawn:f64, dusk:f64) -> f64 {
-    let now: DateTime<Local> = Local::now();
-    let hour = now.hour() as f64;
-    let minute = now.minute() as f64;
-    let second = now.second() as f64;
-    let time = hour + minute / 60.0 + second / 3600.0;
+    let time = hours(Local::now());
     info!("Time is {}", time);
+    day_phase(time, dawn, dusk)
+}
+
+/// Hours since local midnight.
+fn hours<Tz: TimeZone>(t: DateTime<Tz>) -> f64 {
+    t.hour() as f64 + t.minute() as f64 / 60.0 + t.second() as f64 / 3600.0
+}
+
+/// Position in the light cycle as an angle, rising from 0 at dawn to PI at dusk
+/// and held at PI through the night.
+fn day_phase(time: f64, dawn: f64, dusk: f64) -> f64 {
     let x: f64 = (time + 24.0 - dawn) % 24.0;
     let y: f64 = (dusk + 24.0 - dawn) % 24.0;
-    let alt = (x / y) * std::f64::consts::PI;
-    alt
+    if x >= y {
+        // past dusk, or a day with no length at all
+        return std::f64::consts::PI
+    }
+    (x / y) * std::f64::consts::PI
+}
+
+/// Light cycle angle from the sun at `latitude`, `longitude`, `date_offset` days from today.
+/// By default the day is an arc between the real sunrise and sunset, so the photoperiod is natural
+/// but every day peaks at full brightness. With `solar_altitude` brightness follows the sun's
+/// elevation, scaled so it peaks at full brightness at solar noon.
+fn calc_ephemera(latitude: f64, longitude: f64, date_offset: i64, solar_altitude: bool) -> f64 {
+    let now = Local::now() + chrono::Duration::days(date_offset);
+    let altitude_at = |t: DateTime<Utc>| sun::pos(t.timestamp_millis(), latitude, longitude).altitude;
+    let times = sun_times::sun_times(now.date_naive(), latitude, longitude, 0.0);
+    let (sunrise, sunset) = match times {
+        Some(times) => times,
+        None => {
+            // no sunrise or sunset, so the sun is either up or down all day
+            let up = altitude_at(now.with_timezone(&Utc)) > 0.0;
+            info!("No sunrise on {}, polar {}", now.date_naive(), if up {"day"} else {"night"});
+            return if up {std::f64::consts::FRAC_PI_2} else {0.0}
+        }
+    };
+    info!("Sunrise {} sunset {} on {}", sunrise.with_timezone(&Local).format("%H:%M:%S"),
+        sunset.with_timezone(&Local).format("%H:%M:%S"), now.date_naive());
+    if solar_altitude {
+        let noon = sunrise + (sunset - sunrise) / 2;
+        let altitude = altitude_at(now.with_timezone(&Utc));
+        let peak = altitude_at(noon);
+        info!("Sun altitude {:.1} degrees, {:.1} at noon", altitude.to_degrees(), peak.to_degrees());
+        (altitude.sin() / peak.sin()).clamp(-1.0, 1.0).asin()
+    } else {
+        let time = hours(now);
+        info!("Time is {}", time);
+        day_phase(time, hours(sunrise.with_timezone(&Local)), hours(sunset.with_timezone(&Local)))
+    }
+}
+
+
+/// Brightness for a light cycle angle, `night` outside 0 to PI and rising
+/// to `day` at PI / 2.
+fn calc_brightness(altitude: f64, night: u8, day: u8) -> u8 {
+    if !(0.0..std::f64::consts::PI).contains(&altitude) {
+        return night
+    }
+    let x = altitude.sin().max(0.0);
+    (night as f64 + (day as f64 - night as f64) * x).round() as u8
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NIGHT: u8 = 5;
+    const DAY: u8 = 200;
 
-fn calc_brightness(altitude: f64, max_brightness: u8) -> i8 {
-    let x = altitude.sin() * (max_brightness as f64);
-    let y = x.round() as i8;
-    if y > 0 { y } else { 0 }
+    /// Brightness every minute of a day, starting at midnight.
+    fn day(dawn: f64, dusk: f64) -> Vec<(f64, u8)> {
+        (0..24 * 60)
+            .map(|minute| minute as f64 / 60.0)
+            .map(|time| (time, calc_brightness(day_phase(time, dawn, dusk), NIGHT, DAY)))
+            .collect()
+    }
+
+    /// Hours since dawn, wrapping past midnight.
+    fn since(time: f64, dawn: f64) -> f64 {
+        (time + 24.0 - dawn) % 24.0
+    }
+
+    fn check_day(dawn: f64, dusk: f64) {
+        let length = since(dusk, dawn);
+        let midpoint = (dawn + length / 2.0) % 24.0;
+        let mut levels = day(dawn, dusk);
+        // order the day from dawn so the rise and fall can be followed across midnight
+        levels.sort_by(|a, b| since(a.0, dawn).total_cmp(&since(b.0, dawn)));
+        for window in levels.windows(2) {
+            let ((t0, l0), (t1, l1)) = (window[0], window[1]);
+            if since(t1, dawn) <= length / 2.0 {
+                assert!(l1 >= l0, "{}-{}: fell from {} to {} at {}", dawn, dusk, l0, l1, t1);
+            } else {
+                assert!(l1 <= l0, "{}-{}: rose from {} to {} at {}", dawn, dusk, l0, l1, t1);
+            }
+            if since(t0, dawn) >= length {
+                assert_eq!(l0, NIGHT, "{}-{}: not night at {}", dawn, dusk, t0);
+            }
+        }
+        let at_midpoint = calc_brightness(day_phase(midpoint, dawn, dusk), NIGHT, DAY);
+        assert_eq!(at_midpoint, DAY, "{}-{}: not day at midpoint {}", dawn, dusk, midpoint);
+    }
+
+    #[test]
+    fn long_day() {
+        check_day(8.0, 20.0);
+    }
+
+    #[test]
+    fn short_days() {
+        check_day(8.0, 18.0);
+        check_day(8.0, 16.0);
+    }
+
+    #[test]
+    fn day_across_midnight() {
+        check_day(20.0, 8.0);
+    }
+
+    #[test]
+    fn night_before_dawn() {
+        assert_eq!(calc_brightness(day_phase(4.0, 8.0, 16.0), NIGHT, DAY), NIGHT);
+        assert_eq!(calc_brightness(day_phase(6.0, 8.0, 18.0), NIGHT, DAY), NIGHT);
+        assert_eq!(calc_brightness(day_phase(7.5, 8.0, 18.0), NIGHT, DAY), NIGHT);
+    }
+
+    #[test]
+    fn short_scheduled_day() {
+        let path = std::env::temp_dir().join(format!("house-light-schedule-{}", std::process::id()));
+        std::fs::write(&path, "2024-03-01..2024-03-14   08:00  18:00  200\n").unwrap();
+        let schedule = Schedule::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let (rule, _) = schedule.rule_for(NaiveDate::from_ymd_opt(2024, 3, 7).unwrap());
+        let level = |time: f64| calc_brightness(day_phase(time, rule.dawn, rule.dusk), NIGHT, rule.peak);
+        for time in [0.0, 4.0, 6.0, 7.5, 18.0, 20.0, 23.9] {
+            assert_eq!(level(time), NIGHT, "not night at {}", time);
+        }
+        assert_eq!(level(13.0), rule.peak);
+        assert!(level(9.0) > NIGHT && level(9.0) < level(11.0));
+    }
+
+    #[test]
+    fn no_day_length() {
+        for (time, level) in day(8.0, 8.0) {
+            assert_eq!(level, NIGHT, "not night at {}", time);
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,214 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use chrono::{prelude::*, Duration};
+use crate::lib::Error;
+
+/// Light cycle for one day: dawn and dusk as hours since midnight, and the peak brightness.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rule {
+    pub dawn: f64,
+    pub dusk: f64,
+    pub peak: u8,
+}
+
+impl Rule {
+    fn interpolate(&self, other: &Rule, t: f64) -> Rule {
+        Rule {
+            dawn: self.dawn + (other.dawn - self.dawn) * t,
+            dusk: self.dusk + (other.dusk - self.dusk) * t,
+            peak: (self.peak as f64 + (other.peak as f64 - self.peak as f64) * t).round() as u8,
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dawn {} dusk {} peak {}", clock(self.dawn), clock(self.dusk), self.peak)
+    }
+}
+
+fn clock(hours: f64) -> String {
+    let minutes = (hours * 60.0).round() as i64;
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    start: NaiveDate,
+    end: NaiveDate,
+    rule: Rule,
+}
+
+/// Timetable of light cycles by date.
+///
+/// Each line gives a date or inclusive date range and the rule for those days.
+/// Between entries the rule changes a little each day, so photoperiod shifts are gradual.
+/// Before the first entry and after the last, their rules carry on unchanged.
+///
+/// ```text
+/// # date[..end]            dawn   dusk   peak
+/// 2024-03-01..2024-03-14   08:00  18:00  200
+/// 2024-03-28               06:00  22:00  255
+/// ```
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    entries: Vec<Entry>,
+}
+
+/// Hours since midnight, written as either 6.5 or 06:30.
+fn parse_hours(value: &str) -> Option<f64> {
+    let hours = match value.split_once(':') {
+        Some((h, m)) => h.parse::<f64>().ok()? + m.parse::<f64>().ok()? / 60.0,
+        None => value.parse().ok()?,
+    };
+    if (0.0..24.0).contains(&hours) {Some(hours)} else {None}
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [dates, dawn, dusk, peak] = fields.as_slice() else {
+        return None
+    };
+    let (start, end) = dates.split_once("..").unwrap_or((dates, dates));
+    Some(Entry {
+        start: start.parse().ok()?,
+        end: end.parse().ok()?,
+        rule: Rule {
+            dawn: parse_hours(dawn)?,
+            dusk: parse_hours(dusk)?,
+            peak: peak.parse().ok()?,
+        },
+    })
+}
+
+impl Schedule {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Error::ReadError {source: e, path: path.to_path_buf()})?;
+        let mut entries = Vec::new();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            let entry = parse_entry(line).ok_or_else(|| Error::ScheduleParse {
+                path: path.to_path_buf(),
+                line: line.to_string(),
+            })?;
+            entries.push(entry);
+        }
+        Self::new(entries).map_err(|reason| Error::Schedule {path: path.to_path_buf(), reason})
+    }
+
+    fn new(entries: Vec<Entry>) -> Result<Self, String> {
+        if entries.is_empty() {
+            return Err(String::from("no entries"))
+        }
+        if let Some(entry) = entries.iter().find(|e| e.end < e.start) {
+            return Err(format!("range starting {} ends before it starts", entry.start))
+        }
+        if let Some(pair) = entries.windows(2).find(|w| w[1].start <= w[0].end) {
+            return Err(format!("entry starting {} overlaps or precedes the one before it", pair[1].start))
+        }
+        Ok(Schedule {entries})
+    }
+
+    /// Rule for `date`, and where it came from.
+    pub fn rule_for(&self, date: NaiveDate) -> (Rule, String) {
+        let first = &self.entries[0];
+        if date < first.start {
+            return (first.rule, format!("before the first entry, using {}", first.start))
+        }
+        for pair in self.entries.windows(2) {
+            let (current, next) = (&pair[0], &pair[1]);
+            if date <= current.end {
+                return (current.rule, format!("entry {} to {}", current.start, current.end))
+            }
+            if date < next.start {
+                let t = (date - current.end).num_days() as f64 / (next.start - current.end).num_days() as f64;
+                return (current.rule.interpolate(&next.rule, t),
+                        format!("{:.0}% of the way from {} to {}", t * 100.0, current.end, next.start))
+            }
+        }
+        let last = &self.entries[self.entries.len() - 1];
+        if date <= last.end {
+            (last.rule, format!("entry {} to {}", last.start, last.end))
+        } else {
+            (last.rule, format!("after the last entry, using {}", last.start))
+        }
+    }
+
+    /// Next dawn or dusk after `now`, as ("dawn" or "dusk", time).
+    pub fn next_transition(&self, now: DateTime<Local>) -> Option<(&'static str, DateTime<Local>)> {
+        let today = now.date_naive();
+        (0..=2)
+            .map(|days| today + Duration::days(days))
+            .flat_map(|date| {
+                let (rule, _) = self.rule_for(date);
+                [("dawn", rule.dawn), ("dusk", rule.dusk)].map(|(name, hours)| (name, at(date, hours)))
+            })
+            .filter_map(|(name, time)| Some((name, time?)))
+            .filter(|(_, time)| *time > now)
+            .min_by_key(|(_, time)| *time)
+    }
+
+    /// Active rule and next transition, for logging.
+    pub fn status(&self, now: DateTime<Local>) -> String {
+        let (rule, source) = self.rule_for(now.date_naive());
+        match self.next_transition(now) {
+            Some((name, time)) => format!("{} ({}), next {} at {}", rule, source, name, time.format("%Y-%m-%d %H:%M")),
+            None => format!("{} ({})", rule, source),
+        }
+    }
+}
+
+/// Local time `hours` after midnight on `date`.
+fn at(date: NaiveDate, hours: f64) -> Option<DateTime<Local>> {
+    let naive = date.and_hms_opt(0, 0, 0)? + Duration::seconds((hours * 3600.0).round() as i64);
+    Local.from_local_datetime(&naive).earliest()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(value: &str) -> NaiveDate {
+        value.parse().unwrap()
+    }
+
+    fn schedule() -> Schedule {
+        Schedule::new(vec![
+            parse_entry("2024-03-01..2024-03-14 08:00 18:00 200").unwrap(),
+            parse_entry("2024-03-28 06:00 22:00 255").unwrap(),
+        ]).unwrap()
+    }
+
+    #[test]
+    fn rule_inside_entries() {
+        let schedule = schedule();
+        let early = Rule {dawn: 8.0, dusk: 18.0, peak: 200};
+        let late = Rule {dawn: 6.0, dusk: 22.0, peak: 255};
+        assert_eq!(schedule.rule_for(date("2024-02-01")).0, early);
+        assert_eq!(schedule.rule_for(date("2024-03-01")).0, early);
+        assert_eq!(schedule.rule_for(date("2024-03-14")).0, early);
+        assert_eq!(schedule.rule_for(date("2024-03-28")).0, late);
+        assert_eq!(schedule.rule_for(date("2024-06-01")).0, late);
+    }
+
+    #[test]
+    fn rule_between_entries() {
+        let schedule = schedule();
+        // halfway from the end of the first entry to the start of the second
+        let (rule, _) = schedule.rule_for(date("2024-03-21"));
+        assert_eq!(rule, Rule {dawn: 7.0, dusk: 20.0, peak: 228});
+        let (rule, _) = schedule.rule_for(date("2024-03-15"));
+        assert!((rule.dawn - (8.0 - 2.0 / 14.0)).abs() < 1e-9);
+        assert!((rule.dusk - (18.0 + 4.0 / 14.0)).abs() < 1e-9);
+        // each day moves on from the one before
+        let mut previous = schedule.rule_for(date("2024-03-14")).0;
+        for day in 15..=28 {
+            let (rule, _) = schedule.rule_for(date(&format!("2024-03-{}", day)));
+            assert!(rule.dawn < previous.dawn && rule.dusk > previous.dusk && rule.peak >= previous.peak);
+            previous = rule;
+        }
+    }
+}
\ No newline at end of file
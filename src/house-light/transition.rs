@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+/// Time between brightness updates during a fade.
+const STEP: Duration = Duration::from_millis(20);
+
+/// Gradual change between two brightness levels.
+///
+/// Levels are interpolated linearly in perceived brightness, `(level / 255) ^ (1 / gamma)`,
+/// so a fade looks even instead of rushing through the dim end of the range.
+pub struct Fade {
+    from: f64,
+    to: f64,
+    duration: Duration,
+    gamma: f64,
+}
+
+impl Fade {
+    pub fn new(from: u8, to: u8, duration: Duration, gamma: f64) -> Self {
+        let perceived = |level: u8| (level as f64 / 255.0).powf(1.0 / gamma);
+        Fade {
+            from: perceived(from),
+            to: perceived(to),
+            duration,
+            gamma,
+        }
+    }
+
+    /// Brightness `elapsed` into the fade.
+    pub fn level_at(&self, elapsed: Duration) -> u8 {
+        let t = if self.duration.is_zero() {
+            1.0
+        } else {
+            (elapsed.as_secs_f64() / self.duration.as_secs_f64()).min(1.0)
+        };
+        let perceived = self.from + (self.to - self.from) * t;
+        (255.0 * perceived.powf(self.gamma)).round() as u8
+    }
+
+    /// Offsets from the start of the fade at which the brightness changes, with the
+    /// level to write then. Ends with the target level at the full duration.
+    pub fn steps(&self) -> Vec<(Duration, u8)> {
+        let mut steps: Vec<(Duration, u8)> = Vec::new();
+        let mut last = self.level_at(Duration::ZERO);
+        let mut elapsed = STEP;
+        while elapsed < self.duration {
+            let level = self.level_at(elapsed);
+            if level != last {
+                steps.push((elapsed, level));
+                last = level;
+            }
+            elapsed += STEP;
+        }
+        steps.push((self.duration, self.level_at(self.duration)));
+        steps
+    }
+}
\ No newline at end of file
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use crate::lib::{self, Conditioning, Error, Stimulus};
+use crate::mixer;
+
+/// Stimuli decoded ahead of time and held in memory, keyed by file stem.
+pub struct StimulusCache {
+    channels: usize,
+    sample_rate: usize,
+    route: Option<String>,
+    conditioning: Conditioning,
+    stimuli: HashMap<String, Stimulus>,
+}
+
+impl StimulusCache {
+    pub fn new(channels: usize, sample_rate: usize, route: Option<String>, conditioning: Conditioning) -> Self {
+        StimulusCache {
+            channels,
+            sample_rate,
+            route,
+            conditioning,
+            stimuli: HashMap::new(),
+        }
+    }
+
+    pub fn preload(&mut self, path: &Path) -> Result<&Stimulus, Error> {
+        let stimulus = lib::load_audio(path, self.channels, self.route.as_deref(), &self.conditioning)?;
+        let name = stimulus.name.clone();
+        if stimulus.sample_rate != self.sample_rate {
+            warn!("Stimulus {} sampled at {}Hz but device runs at {}Hz",
+                name, stimulus.sample_rate, self.sample_rate);
+        }
+        if self.stimuli.insert(name.clone(), stimulus).is_some() {
+            warn!("Stimulus {} loaded twice, keeping {:?}", name, path);
+        }
+        Ok(&self.stimuli[&name])
+    }
+
+    /// Add a stimulus that didn't come from disk, such as a synthesized one.
+    pub fn insert(&mut self, stimulus: Stimulus) -> &Stimulus {
+        let name = stimulus.name.clone();
+        self.stimuli.insert(name.clone(), stimulus);
+        &self.stimuli[&name]
+    }
+
+    /// Load every wav file in `dir`. Returns the names loaded, sorted.
+    pub fn preload_dir(&mut self, dir: &Path) -> Result<Vec<String>, Error> {
+        let entries = fs::read_dir(dir)
+            .map_err(|e| Error::ListRead {source: e, path: dir.display().to_string()})?;
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wav"))
+            .collect();
+        paths.sort();
+        let mut names = Vec::with_capacity(paths.len());
+        for path in paths {
+            names.push(self.preload(&path)?.name.clone());
+        }
+        info!("Preloaded {} stimuli from {:?}", names.len(), dir);
+        Ok(names)
+    }
+
+    /// Load the stimuli listed in a manifest file: one path per line, relative
+    /// to the manifest's directory, optionally followed by a playlist weight.
+    /// Blank lines and lines starting with '#' are skipped.
+    /// Returns each loaded name with its weight (1.0 if none is given).
+    pub fn preload_manifest(&mut self, manifest: &Path) -> Result<Vec<(String, f64)>, Error> {
+        let list_err = |e| Error::ListRead {source: e, path: manifest.display().to_string()};
+        let contents = fs::read_to_string(manifest).map_err(list_err)?;
+        let base = manifest.parent().unwrap_or(Path::new("."));
+        let mut entries = Vec::new();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            let mut fields = line.split_whitespace();
+            let file = fields.next().unwrap();
+            let weight = match fields.next() {
+                Some(w) => w.parse::<f64>().map_err(|_| Error::ManifestWeight {line: line.to_string()})?,
+                None => 1.0,
+            };
+            let name = self.preload(&base.join(file))?.name.clone();
+            entries.push((name, weight));
+        }
+        info!("Preloaded {} stimuli from manifest {:?}", entries.len(), manifest);
+        Ok(entries)
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Stimulus, Error> {
+        self.stimuli.get(name).ok_or_else(|| Error::MissingStimulus {name: name.to_string()})
+    }
+
+    /// Scale every loaded stimulus by the gain in dB that `gain` returns for it.
+    pub fn apply_gain<F>(&mut self, mut gain: F)
+        where F: FnMut(&Stimulus) -> f64 {
+        for stimulus in self.stimuli.values_mut() {
+            let db = gain(stimulus);
+            if db == 0.0 {
+                continue
+            }
+            info!("applying {:.1}dB gain to stimulus {}", db, stimulus.name);
+            let clipped = mixer::apply_gain(&mut stimulus.samples, db);
+            if clipped > 0 {
+                warn!("{}dB gain clipped {} samples of stimulus {}", db, clipped, stimulus.name);
+            }
+        }
+    }
+
+    /// Join stimuli end to end into one buffer so they play without gaps.
+    pub fn concat<'a, I>(&self, names: I) -> Result<Vec<i16>, Error>
+        where I: IntoIterator<Item = &'a str> {
+        let mut block = Vec::new();
+        for name in names {
+            block.extend_from_slice(&self.get(name)?.samples);
+        }
+        Ok(block)
+    }
+}
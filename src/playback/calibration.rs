@@ -0,0 +1,118 @@
+use std::fs;
+use std::path::Path;
+use log::warn;
+use crate::lib::{Error, Stimulus};
+
+/// Measured sound level of a rig's speaker.
+///
+/// The calibration file lists the dB SPL measured while playing a reference
+/// signal of known RMS level, at several digital gains and a fixed mixer volume:
+///
+/// ```text
+/// # RMS of the reference signal in dBFS, defaults to a full-scale sine
+/// reference -20
+/// # gain_db  spl_db
+/// -30 52.1
+/// -20 62.0
+/// -10 71.8
+/// ```
+#[derive(Clone, Debug)]
+pub struct Calibration {
+    reference_dbfs: f64,
+    /// (gain dB, SPL dB) sorted by gain
+    points: Vec<(f64, f64)>,
+}
+
+/// RMS level of a full-scale sine wave.
+const FULL_SCALE_SINE_DBFS: f64 = -3.0103;
+
+impl Calibration {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let display = path.display().to_string();
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Error::CalibrationRead {source: e, path: display.clone()})?;
+        let parse_err = |line: &str| Error::CalibrationParse {path: display.clone(), line: line.to_string()};
+
+        let mut reference_dbfs = FULL_SCALE_SINE_DBFS;
+        let mut points = Vec::new();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["reference", level] => {
+                    reference_dbfs = level.parse().map_err(|_| parse_err(line))?;
+                },
+                [gain, spl] => {
+                    let gain: f64 = gain.parse().map_err(|_| parse_err(line))?;
+                    let spl: f64 = spl.parse().map_err(|_| parse_err(line))?;
+                    points.push((gain, spl));
+                },
+                _ => return Err(parse_err(line)),
+            }
+        }
+        Self::new(reference_dbfs, points)
+    }
+
+    pub fn new(reference_dbfs: f64, mut points: Vec<(f64, f64)>) -> Result<Self, Error> {
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if points.len() < 2 || points.windows(2).any(|w| w[1].1 <= w[0].1) {
+            return Err(Error::Calibration {
+                reason: String::from("need at least two measurements, rising in level with gain")
+            })
+        }
+        Ok(Calibration {
+            reference_dbfs,
+            points,
+        })
+    }
+
+    /// Digital gain that plays a signal with RMS `rms_dbfs` at `target_spl`.
+    /// Outside the measured range the nearest segment of the curve is extended.
+    pub fn gain_for_level(&self, rms_dbfs: f64, target_spl: f64) -> f64 {
+        // level the reference signal would need to reach for this signal to hit the target
+        let spl = target_spl - (rms_dbfs - self.reference_dbfs);
+        let (lowest, highest) = (self.points[0], self.points[self.points.len() - 1]);
+        if spl < lowest.1 || spl > highest.1 {
+            warn!("{} dB SPL is outside the calibrated range of {} to {} dB SPL",
+                target_spl, lowest.1 + rms_dbfs - self.reference_dbfs, highest.1 + rms_dbfs - self.reference_dbfs);
+        }
+        let segment = self.points.windows(2)
+            .find(|w| spl <= w[1].1)
+            .unwrap_or(&self.points[self.points.len() - 2..]);
+        let ((g0, s0), (g1, s1)) = (segment[0], segment[1]);
+        g0 + (spl - s0) * (g1 - g0) / (s1 - s0)
+    }
+
+    /// Digital gain that plays `stimulus` at `target_spl`, warning if it would clip.
+    pub fn gain_for(&self, stimulus: &Stimulus, target_spl: f64) -> f64 {
+        let rms = rms_dbfs(&stimulus.samples);
+        if !rms.is_finite() {
+            warn!("stimulus {} is silent, leaving it unscaled", stimulus.name);
+            return 0.0
+        }
+        let gain = self.gain_for_level(rms, target_spl);
+        let peak = peak_dbfs(&stimulus.samples);
+        if peak + gain > 0.0 {
+            warn!("stimulus {} at {} dB SPL needs {:.1}dB gain and would clip by {:.1}dB",
+                stimulus.name, target_spl, gain, peak + gain);
+        }
+        gain
+    }
+}
+
+pub fn rms_dbfs(samples: &[i16]) -> f64 {
+    if samples.is_empty() {
+        return f64::NEG_INFINITY
+    }
+    let mean_square = samples.iter()
+        .map(|&s| (s as f64 / 32768.0).powi(2))
+        .sum::<f64>() / samples.len() as f64;
+    10.0 * mean_square.log10()
+}
+
+pub fn peak_dbfs(samples: &[i16]) -> f64 {
+    let peak = samples.iter().map(|&s| (s as i32).abs()).max().unwrap_or(0);
+    20.0 * (peak as f64 / 32768.0).log10()
+}
@@ -0,0 +1,125 @@
+use std::fs;
+use alsa::{card, Ctl, Direction, PCM};
+use alsa::ctl::DeviceIter;
+use alsa::pcm::{Format, HwParams};
+use log::info;
+use crate::lib::Error;
+
+/// Sample formats checked when describing a device.
+const FORMATS: [Format; 5] = [Format::S16LE, Format::S243LE, Format::S24LE, Format::S32LE, Format::FloatLE];
+
+#[derive(Clone, Debug)]
+pub struct CardDescription {
+    pub index: i32,
+    /// short ALSA id, stable across reboots, e.g. "Device" or "sndrpihifiberry"
+    pub id: String,
+    pub name: String,
+    pub longname: String,
+    /// "vendor:product" for USB cards
+    pub usb_id: Option<String>,
+    pub pcms: Vec<PcmDescription>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PcmDescription {
+    pub device: u32,
+    pub name: String,
+    /// (min, max) sample rate, unknown if the device was busy
+    pub rates: Option<(u32, u32)>,
+    /// (min, max) channel count, unknown if the device was busy
+    pub channels: Option<(u32, u32)>,
+    pub formats: Vec<Format>,
+}
+
+impl CardDescription {
+    /// Device string for the ALSA plug layer, which converts rate and format as needed.
+    pub fn plughw(&self) -> String {
+        format!("plughw:{}", self.index)
+    }
+    /// Device string for the card itself, used for the mixer.
+    pub fn hw(&self) -> String {
+        format!("hw:{}", self.index)
+    }
+
+    fn matches(&self, selector: &str) -> bool {
+        selector.parse::<i32>().is_ok_and(|index| index == self.index)
+            || self.id == selector
+            || self.name == selector
+            || self.usb_id.as_deref().is_some_and(|usb| usb.eq_ignore_ascii_case(selector))
+    }
+}
+
+fn usb_id(index: i32) -> Option<String> {
+    fs::read_to_string(format!("/proc/asound/card{}/usbid", index))
+        .ok()
+        .map(|id| id.trim().to_string())
+}
+
+fn describe_pcm(ctl: &Ctl, card: i32, device: u32, direction: Direction) -> Option<PcmDescription> {
+    let name = ctl.pcm_info(device, 0, direction).ok()?
+        .get_name().unwrap_or("unnamed").to_string();
+    let mut description = PcmDescription {
+        device,
+        name,
+        rates: None,
+        channels: None,
+        formats: Vec::new(),
+    };
+    // opening can fail if another process holds the device; keep what ctl reported
+    if let Ok(pcm) = PCM::new(&format!("hw:{},{}", card, device), direction, true) {
+        if let Ok(hwp) = HwParams::any(&pcm) {
+            description.rates = hwp.get_rate_min().and_then(|min| Ok((min, hwp.get_rate_max()?))).ok();
+            description.channels = hwp.get_channels_min().and_then(|min| Ok((min, hwp.get_channels_max()?))).ok();
+            description.formats = FORMATS.iter().copied().filter(|f| hwp.test_format(*f).is_ok()).collect();
+        }
+    }
+    Some(description)
+}
+
+fn describe_card(card: &alsa::Card, direction: Direction) -> Result<CardDescription, alsa::Error> {
+    let ctl = Ctl::from_card(card, false)?;
+    let card_info = ctl.card_info()?;
+    let index = card.get_index();
+    let pcms = DeviceIter::new(&ctl)
+        .filter_map(|device| describe_pcm(&ctl, index, device as u32, direction))
+        .collect();
+    Ok(CardDescription {
+        index,
+        id: card_info.get_id()?.to_string(),
+        name: card_info.get_name()?.to_string(),
+        longname: card_info.get_longname()?.to_string(),
+        usb_id: usb_id(index),
+        pcms,
+    })
+}
+
+/// Every sound card with its devices for the given stream direction.
+pub fn list_cards(direction: Direction) -> Result<Vec<CardDescription>, Error> {
+    card::Iter::new()
+        .map(|card| card.and_then(|c| describe_card(&c, direction)))
+        .collect::<Result<Vec<_>, alsa::Error>>()
+        .map_err(|e| Error::DeviceList {source: e})
+}
+
+/// Find a card by index, ALSA id, name or USB "vendor:product" id.
+pub fn find_card(selector: &str, direction: Direction) -> Result<CardDescription, Error> {
+    let cards = list_cards(direction)?;
+    cards.into_iter()
+        .find(|card| card.matches(selector))
+        .ok_or_else(|| Error::NoCard {selector: selector.to_string()})
+}
+
+pub fn log_inventory(cards: &[CardDescription]) {
+    if cards.is_empty() {
+        info!("No sound cards found");
+    }
+    for card in cards {
+        info!("card {}: {} [{}] usb {} - {}",
+            card.index, card.id, card.name, card.usb_id.as_deref().unwrap_or("none"), card.longname);
+        for pcm in &card.pcms {
+            let formats: Vec<String> = pcm.formats.iter().map(Format::to_string).collect();
+            info!("  device {}: {} rates {:?} channels {:?} formats [{}]",
+                pcm.device, pcm.name, pcm.rates, pcm.channels, formats.join(", "));
+        }
+    }
+}
@@ -0,0 +1,535 @@
+use std::f64::consts::PI;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use alsa::ValueOr;
+use alsa::pcm::{PCM, HwParams, Format, Access};
+use log::{info, warn};
+use sndfile::{self, ReadOptions, SndFileIO};
+use thiserror;
+use crate::sink::PcmSink;
+use crate::trigger::Trigger;
+
+/// Decoded audio, already routed to the device channel layout.
+#[derive(Clone, Debug)]
+pub struct Stimulus {
+    pub name: String,
+    pub samples: Vec<i16>,
+    pub sample_rate: usize,
+}
+
+/// Clean-up applied to stimuli as they are loaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Conditioning {
+    /// subtract each file channel's mean before routing
+    pub remove_dc: bool,
+    /// scale so the loudest sample peaks at this level in dBFS
+    pub normalize_dbfs: Option<f64>,
+}
+
+impl Conditioning {
+    fn is_none(&self) -> bool {
+        !self.remove_dc && self.normalize_dbfs.is_none()
+    }
+}
+
+/// Decode a wav file and route it to `channels` device channels,
+/// using `route` as a channel map spec if given.
+pub fn load_audio(path: &Path, channels: usize, route: Option<&str>, conditioning: &Conditioning)
+                  -> Result<Stimulus, Error> {
+    let display = path.display().to_string();
+    if !path.extension().is_some_and(|ext| ext == "wav") {
+        return Err(Error::NotAudio {path: display})
+    }
+    let name = path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| display.clone());
+    let mut audio_file = sndfile::OpenOptions::ReadOnly(ReadOptions::Auto)
+        .from_path(path)
+        .map_err(|_| Error::FileOpen {path: display.clone()})?;
+    let wav: Vec<i16> = audio_file.read_all_to_vec()
+        .map_err(|_| Error::FileRead {path: display.clone()})?;
+    let wav_channels = audio_file.get_channels();
+    let channel_map = match route {
+        Some(spec) => ChannelMap::from_spec(spec, wav_channels, channels),
+        None => ChannelMap::default_for(wav_channels, channels),
+    }?;
+    Ok(Stimulus {
+        name,
+        samples: process_audio(wav, &channel_map, conditioning)?,
+        sample_rate: audio_file.get_samplerate(),
+    })
+}
+
+/// Routing matrix from file channels to hardware channels.
+/// `weights[out * inputs + inp]` is the gain applied to input channel `inp`
+/// when it is summed into output channel `out`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelMap {
+    inputs: usize,
+    outputs: usize,
+    weights: Vec<f32>,
+}
+
+impl ChannelMap {
+    pub fn new(inputs: usize, outputs: usize, weights: Vec<f32>) -> Result<Self, Error> {
+        if inputs == 0 || outputs == 0 {
+            return Err(Error::UnsupportedLayout {inputs, outputs})
+        }
+        if weights.len() != inputs * outputs {
+            return Err(Error::MapSize {expected: inputs * outputs, found: weights.len()})
+        }
+        Ok(ChannelMap{
+            inputs,
+            outputs,
+            weights
+        })
+    }
+
+    /// Default routing between two layouts: identity when the counts match,
+    /// mono copied to every output, and an equal-weight average when downmixing to mono.
+    pub fn default_for(inputs: usize, outputs: usize) -> Result<Self, Error> {
+        let weights: Vec<f32> = if inputs == outputs {
+            (0..outputs).flat_map(|out| (0..inputs).map(move |inp| if inp == out {1.0} else {0.0}))
+                .collect()
+        } else if inputs == 1 {
+            vec![1.0; outputs]
+        } else if outputs == 1 {
+            vec![1.0 / inputs as f32; inputs]
+        } else {
+            return Err(Error::UnsupportedLayout {inputs, outputs})
+        };
+        Self::new(inputs, outputs, weights)
+    }
+
+    /// Parse a routing spec of one row per output channel separated by ';',
+    /// each row holding one comma-separated weight per input channel.
+    /// e.g. "1;0" sends a mono file to the left speaker only,
+    /// and "0.7,0.3" downmixes stereo to mono favouring the left channel.
+    pub fn from_spec(spec: &str, inputs: usize, outputs: usize) -> Result<Self, Error> {
+        let rows: Vec<&str> = spec.split(';').collect();
+        if rows.len() != outputs {
+            return Err(Error::MapSize {expected: inputs * outputs, found: rows.len() * inputs})
+        }
+        let mut weights = Vec::with_capacity(inputs * outputs);
+        for row in rows {
+            let row_weights = row.split(',')
+                .map(|w| w.trim().parse::<f32>()
+                    .map_err(|_| Error::MapParse {spec: spec.to_string()}))
+                .collect::<Result<Vec<f32>, Error>>()?;
+            if row_weights.len() != inputs {
+                return Err(Error::MapSize {expected: inputs * outputs, found: row_weights.len() * outputs})
+            }
+            weights.extend(row_weights);
+        }
+        Self::new(inputs, outputs, weights)
+    }
+
+    pub fn inputs(&self) -> usize { self.inputs }
+
+    pub fn is_identity(&self) -> bool {
+        self.inputs == self.outputs && self.weights.iter().enumerate()
+            .all(|(i, &w)| w == if i / self.inputs == i % self.inputs {1.0} else {0.0})
+    }
+
+    /// Route interleaved file frames to the output layout, subtracting
+    /// `offsets[inp]` from input channel `inp` first.
+    pub fn mix(&self, wav: &[i16], offsets: &[f32]) -> Vec<f32> {
+        let mut result = Vec::with_capacity(wav.len() / self.inputs * self.outputs);
+        for frame in wav.chunks_exact(self.inputs) {
+            for out in 0..self.outputs {
+                let row = &self.weights[out * self.inputs..(out + 1) * self.inputs];
+                let mixed: f32 = frame.iter()
+                    .zip(offsets)
+                    .zip(row)
+                    .map(|((&sample, &offset), &weight)| (sample as f32 - offset) * weight)
+                    .sum();
+                result.push(mixed);
+            }
+        }
+        result
+    }
+}
+
+/// Mean of each channel of interleaved `wav`.
+fn channel_means(wav: &[i16], channels: usize) -> Vec<f32> {
+    let frames = (wav.len() / channels).max(1) as f64;
+    (0..channels)
+        .map(|ch| (wav.iter().skip(ch).step_by(channels).map(|&s| s as f64).sum::<f64>() / frames) as f32)
+        .collect()
+}
+
+pub fn process_audio(wav: Vec<i16>, map: &ChannelMap, conditioning: &Conditioning) -> Result<Vec<i16>, Error> {
+    if wav.len() % map.inputs() != 0 {
+        return Err(Error::PartialFrame {samples: wav.len(), channels: map.inputs()})
+    }
+    if map.is_identity() && conditioning.is_none() {
+        return Ok(wav)
+    }
+    let offsets = if conditioning.remove_dc {
+        channel_means(&wav, map.inputs())
+    } else {
+        vec![0.0; map.inputs()]
+    };
+    let mut mixed = map.mix(&wav, &offsets);
+    if let Some(dbfs) = conditioning.normalize_dbfs {
+        let peak = mixed.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+        if peak > 0.0 {
+            let gain = (10f64.powf(dbfs / 20.0) * i16::MAX as f64 / peak as f64) as f32;
+            mixed.iter_mut().for_each(|s| *s *= gain);
+        }
+    }
+    Ok(mixed.into_iter()
+        .map(|s| s.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+        .collect())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RampShape {
+    /// raised cosine, the usual choice for avoiding spectral splatter
+    Cosine,
+    Linear,
+}
+
+impl FromStr for RampShape {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cosine" => Ok(RampShape::Cosine),
+            "linear" => Ok(RampShape::Linear),
+            _ => Err(format!("unknown ramp shape '{}', expected cosine or linear", s)),
+        }
+    }
+}
+
+/// Amplitude ramp applied at a stimulus' edges.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ramp {
+    pub shape: RampShape,
+    pub frames: usize,
+}
+
+impl Ramp {
+    /// Gain for a frame `edge` frames away from the nearest edge,
+    /// 0.0 at the edge and 1.0 once past the ramp.
+    pub fn gain(&self, edge: usize) -> f64 {
+        if edge >= self.frames {
+            return 1.0
+        }
+        let x = edge as f64 / self.frames as f64;
+        match self.shape {
+            RampShape::Cosine => 0.5 * (1.0 - (PI * x).cos()),
+            RampShape::Linear => x,
+        }
+    }
+}
+
+/// Requested ALSA hardware parameters. Times are in microseconds; when only one of
+/// buffer and period time is given the other is derived from it.
+#[derive(Clone, Debug)]
+pub struct HwConfig {
+    pub channels: usize,
+    pub sample_rate: u32,
+    pub buffer_time: Option<u32>,
+    pub period_time: Option<u32>,
+}
+
+/// Hardware parameters as accepted by the device.
+#[derive(Clone, Copy, Debug)]
+pub struct HwSettings {
+    pub channels: usize,
+    pub sample_rate: u32,
+    /// in frames
+    pub buffer_size: usize,
+    /// in frames
+    pub period_size: usize,
+}
+
+const PERIODS_PER_BUFFER: u32 = 4;
+/// About 1024 frames at 44.1kHz
+const DEFAULT_BUFFER_TIME: u32 = 23_220;
+
+impl HwConfig {
+    /// Buffer and period time in microseconds, filling in whichever wasn't given.
+    fn times(&self) -> (u32, u32) {
+        match (self.buffer_time, self.period_time) {
+            (Some(buffer), Some(period)) => (buffer, period),
+            (Some(buffer), None) => (buffer, buffer / PERIODS_PER_BUFFER),
+            (None, Some(period)) => (period * PERIODS_PER_BUFFER, period),
+            (None, None) => (DEFAULT_BUFFER_TIME, DEFAULT_BUFFER_TIME / PERIODS_PER_BUFFER),
+        }
+    }
+
+    /// Settings as if a device accepted this request exactly, for sinks without hardware.
+    pub fn nominal(&self) -> HwSettings {
+        let (buffer_time, period_time) = self.times();
+        let frames = |us: u32| ((us as u64 * self.sample_rate as u64 / 1_000_000) as usize).max(1);
+        HwSettings {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            buffer_size: frames(buffer_time),
+            period_size: frames(period_time),
+        }
+    }
+}
+
+pub fn get_hw_config(pcm: &PCM, config: &HwConfig) -> Result<HwSettings, Error> {
+    let hw_err = |e| Error::HwParams {source: e};
+    let hwp = HwParams::any(&pcm).map_err(hw_err)?;
+    hwp.set_channels(config.channels as u32).map_err(hw_err)?;
+    hwp.set_rate(config.sample_rate, ValueOr::Nearest).map_err(hw_err)?;
+    hwp.set_access(Access::RWInterleaved).map_err(hw_err)?;
+    hwp.set_format(Format::s16()).map_err(hw_err)?;
+    let (buffer_time, period_time) = config.times();
+    let period_time = hwp.set_period_time_near(period_time, ValueOr::Nearest).map_err(hw_err)?;
+    let buffer_time = hwp.set_buffer_time_near(buffer_time, ValueOr::Nearest).map_err(hw_err)?;
+    pcm.hw_params(&hwp).map_err(hw_err)?;
+
+    let current = pcm.hw_params_current().map_err(hw_err)?;
+    let settings = HwSettings {
+        channels: current.get_channels().map_err(hw_err)? as usize,
+        sample_rate: current.get_rate().map_err(hw_err)?,
+        buffer_size: current.get_buffer_size().map_err(hw_err)? as usize,
+        period_size: current.get_period_size().map_err(hw_err)? as usize,
+    };
+    if settings.sample_rate != config.sample_rate {
+        warn!("device runs at {}Hz instead of the requested {}Hz", settings.sample_rate, config.sample_rate);
+    }
+    info!("hw params: {}Hz, {} channels, buffer {} frames ({}us), period {} frames ({}us)",
+        settings.sample_rate, settings.channels, settings.buffer_size, buffer_time,
+        settings.period_size, period_time);
+    Ok(settings)
+}
+
+/// Timing of a single call to `playback_io`.
+#[derive(Clone, Debug, Default)]
+pub struct PlaybackStats {
+    /// time from the call until the first sample reaches the DAC, from the PCM delay
+    /// reported once the stream is running
+    pub onset_latency: Option<Duration>,
+    /// time spent writing, which ends about one buffer before the last sample is heard
+    pub write_time: Duration,
+    pub underruns: usize,
+    pub frames_written: usize,
+    /// playback was interrupted before all frames were written
+    pub stopped: bool,
+}
+
+impl fmt::Display for PlaybackStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.onset_latency {
+            Some(latency) => write!(f, "onset latency {:.3}ms", latency.as_secs_f64() * 1000.0)?,
+            None => write!(f, "onset latency unknown")?,
+        }
+        write!(f, ", {} frames written in {:.1}ms, {} underruns",
+            self.frames_written, self.write_time.as_secs_f64() * 1000.0, self.underruns)?;
+        if self.stopped {
+            write!(f, ", stopped early")?;
+        }
+        Ok(())
+    }
+}
+
+/// Queue `data` on the sink, returning once the last of it is written, or as soon as
+/// `stop` is set. Call the sink's `finish` afterwards to wait for it to play out.
+///
+/// With a `ramp`, the first and last frames are faded in and out, and a stop fades
+/// out over the ramp length from wherever playback had got to rather than cutting off.
+/// A stop seen before anything is written returns at once without writing or triggering.
+///
+/// A `trigger` is scheduled high for when the first sample is heard and low for when
+/// the last one is, both estimated from the sink's delay.
+pub fn playback_io<S: PcmSink + ?Sized>(sink: &mut S, data: &[i16], stop: &AtomicBool, ramp: Option<&Ramp>,
+                                        trigger: Option<&Trigger>) -> Result<PlaybackStats, Error> {
+    let called = Instant::now();
+    let settings = sink.settings();
+    let channels = settings.channels;
+    // write a period at a time so each call wakes up as soon as there is room
+    let chunk = settings.period_size * channels;
+    let mut stats = PlaybackStats::default();
+    let total_frames = data.len() / channels;
+    // never ramp over more than half the stimulus, so onset and offset don't overlap
+    let ramp = ramp.filter(|r| r.frames > 0)
+        .map(|r| Ramp {frames: r.frames.min(total_frames / 2).max(1), ..*r});
+    let mut scratch: Vec<i16> = Vec::with_capacity(if ramp.is_some() {chunk} else {0});
+    // frame at which output ends, moved earlier when stopped mid-stimulus
+    let mut end_frame = total_frames;
+    let mut fade_end: Option<usize> = None;
+    sink.begin()?;
+    let mut pointer = 0;
+    let mut _written: usize = 0;
+    //loop while playing
+    while pointer < end_frame * channels {
+        if !stats.stopped && stop.load(Ordering::Relaxed) {
+            stats.stopped = true;
+            match ramp {
+                // nothing has been heard yet, so there is nothing to fade out
+                _ if pointer == 0 => return Ok(stats),
+                Some(r) => {
+                    let here = pointer / channels;
+                    end_frame = (here + r.frames).min(end_frame);
+                    fade_end = Some(end_frame);
+                    info!("stopping, fading out over {} frames", end_frame - here);
+                },
+                None => break,
+            }
+        }
+        let end = end_frame * channels;
+        let slice = if pointer+chunk>end {&data[pointer..end]} else {&data[pointer..pointer+chunk]};
+        let slice = match ramp {
+            Some(r) => {
+                let first = pointer / channels;
+                scratch.clear();
+                scratch.extend(slice.chunks_exact(channels).enumerate().flat_map(|(i, frame)| {
+                    let n = first + i;
+                    let mut gain = r.gain(n) * r.gain(total_frames - 1 - n);
+                    if let Some(fade_end) = fade_end {
+                        gain *= r.gain(fade_end - 1 - n);
+                    }
+                    frame.iter().map(move |&s| (s as f64 * gain).round() as i16)
+                }));
+                &scratch[..]
+            },
+            None => slice,
+        };
+        _written = sink.write(slice, &mut stats)?;
+        pointer += _written * channels;
+        stats.frames_written += _written;
+        if stats.onset_latency.is_none() && stats.frames_written > 0 {
+            // delay counts everything queued, including what this call has written so far
+            if let Some(delay) = sink.delay() {
+                let ahead = (delay - stats.frames_written as i64).max(0);
+                let latency = called.elapsed() + Duration::from_secs_f64(ahead as f64 / settings.sample_rate as f64);
+                stats.onset_latency = Some(latency);
+                if let Some(trigger) = trigger {
+                    trigger.schedule(called + latency, true);
+                }
+            }
+        }
+    };
+    stats.write_time = called.elapsed();
+    if let Some(trigger) = trigger {
+        // a stop without a fade drops whatever is still queued
+        let queued = if stats.stopped && ramp.is_none() {0} else {sink.delay().unwrap_or(0).max(0)};
+        trigger.schedule(Instant::now() + Duration::from_secs_f64(queued as f64 / settings.sample_rate as f64), false);
+    }
+    Ok(stats)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Provided file {path} not an audio file")]
+    NotAudio {
+        path: String,
+    },
+    #[error("Failed to open audio file {path}")]
+    FileOpen {
+        path: String,
+    },
+    #[error("Failed to read samples from {path}")]
+    FileRead {
+        path: String,
+    },
+    #[error("Failed to read stimulus list {path}")]
+    ListRead {
+        source: std::io::Error,
+        path: String,
+    },
+    #[error("Invalid weight in stimulus list line '{line}'")]
+    ManifestWeight {
+        line: String,
+    },
+    #[error("Invalid playlist: {reason}")]
+    Playlist {
+        reason: String,
+    },
+    #[error("Invalid synthetic stimulus: {reason}")]
+    Synth {
+        reason: String,
+    },
+    #[error("Failed to access mixer of card {card}")]
+    Mixer {
+        source: alsa::Error,
+        card: String,
+    },
+    #[error("No playback volume control '{name}' on card {card}")]
+    NoMixerControl {
+        card: String,
+        name: String,
+    },
+    #[error("Failed to read calibration file {path}")]
+    CalibrationRead {
+        source: std::io::Error,
+        path: String,
+    },
+    #[error("Invalid line '{line}' in calibration file {path}")]
+    CalibrationParse {
+        path: String,
+        line: String,
+    },
+    #[error("Invalid calibration: {reason}")]
+    Calibration {
+        reason: String,
+    },
+    #[error("Failed to enumerate sound cards")]
+    DeviceList {
+        source: alsa::Error,
+    },
+    #[error("No sound card matching '{selector}'")]
+    NoCard {
+        selector: String,
+    },
+    #[error("Failed to play on device {device}")]
+    Playback {
+        source: alsa::Error,
+        device: String,
+    },
+    #[error("Failed to configure hardware parameters")]
+    HwParams {
+        source: alsa::Error,
+    },
+    #[error("Failed to record from capture device {device}")]
+    Capture {
+        source: alsa::Error,
+        device: String,
+    },
+    #[error("Failed to request trigger line {line} on {chip}")]
+    Trigger {
+        source: gpio_cdev::errors::Error,
+        chip: String,
+        line: u32,
+    },
+    #[error("Failed to create recording file {path}")]
+    FileCreate {
+        path: String,
+    },
+    #[error("Failed to write samples to {path}")]
+    FileWrite {
+        path: String,
+    },
+    #[error("Stimulus '{name}' not loaded")]
+    MissingStimulus {
+        name: String,
+    },
+    #[error("No channel routing for {inputs} file channels to {outputs} device channels")]
+    UnsupportedLayout {
+        inputs: usize,
+        outputs: usize,
+    },
+    #[error("Channel map has {found} weights, expected {expected}")]
+    MapSize {
+        expected: usize,
+        found: usize,
+    },
+    #[error("Couldn't parse channel map '{spec}'")]
+    MapParse {
+        spec: String,
+    },
+    #[error("Audio data of {samples} samples is not a whole number of {channels}-channel frames")]
+    PartialFrame {
+        samples: usize,
+        channels: usize,
+    },
+}
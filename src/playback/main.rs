@@ -1,34 +1,206 @@
+mod lib;
+mod cache;
+mod calibration;
+mod devices;
+mod mixer;
+mod record;
+mod sequence;
+mod sink;
+mod synth;
+mod trigger;
 use alsa::Direction;
-use alsa::pcm::{PCM, HwParams, Format, Access, State};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::time::Duration;
 use argh::{self,FromArgs};
-use sndfile::{ReadOptions, SndFileIO};
 use simple_logger::SimpleLogger;
-use log::info;
-use sndfile;
+use log::{error, info};
+use cache::StimulusCache;
+use lib::{Conditioning, Ramp, RampShape};
+use calibration::Calibration;
+use mixer::Volume;
+use record::{FileFormat, RecordConfig};
+use sequence::{Order, Playlist};
+use sink::{AlsaSink, NullSink, PcmSink, WavSink};
+use synth::{SignalKind, SynthParams, Waveform};
+use trigger::Trigger;
 
 #[derive(FromArgs)]
-/// Playback an audio file with the default audio device
+/// Playback audio with the default audio device
 #[argh(help_triggers("-h", "--help", "help"))]
 struct CliArgs {
-    #[argh(positional)]
-    /// path to wav file
-    wav_file: String,
     #[argh(option, default = "default_device()", short='d')]
     /// playback device, defaults to plughw:1
     device: String,
     #[argh(option, default = "default_card()", short='c')]
     /// playback card, used for volume changing, defaults to hw:1
     card: String,
+    #[argh(option)]
+    /// select the card by ALSA id, name or USB vendor:product id, overrides --device and --card
+    card_id: Option<String>,
+    #[argh(switch, short='l')]
+    /// list sound cards and their playback devices, then exit
+    list: bool,
     #[argh(option, default="default_rate()", short='s')]
     /// sampling rate, defaults to 44100
     sample_rate: u32,
     #[argh(option, default = "default_channel()", short='m')]
     /// channel count, 1 for mono and 2 for stereo
     channel: usize,
+    #[argh(option)]
+    /// ALSA buffer time in us, defaults to four periods or about 1024 frames
+    buffer_time: Option<u32>,
+    #[argh(option)]
+    /// ALSA period time in us, defaults to a quarter of the buffer time
+    period_time: Option<u32>,
     #[argh(option, default = "default_volume()", short='v')]
     /// volume setting between 0 and 100
     volume: i64,
+    #[argh(option)]
+    /// mixer volume in dB, overrides --volume
+    volume_db: Option<f64>,
+    #[argh(option)]
+    /// mixer control to set, defaults to the first of PCM, Master, Speaker, Headphone or Digital found
+    control: Option<String>,
+    #[argh(option, default = "0.0", short='g')]
+    /// digital gain in dB applied to the samples before playback, defaults to 0
+    gain: f64,
+    #[argh(option)]
+    /// target level in dB SPL, scaling each stimulus by its RMS level using --calibration
+    spl: Option<f64>,
+    #[argh(option)]
+    /// calibration file of dB SPL measured against digital gain for this rig
+    calibration: Option<String>,
+    #[argh(option)]
+    /// render to this wav file instead of playing on a device
+    output: Option<String>,
+    #[argh(switch)]
+    /// discard audio instead of playing it, logging when each chunk would have been written
+    null: bool,
+    #[argh(option, default = "default_device()")]
+    /// capture device for recording, defaults to plughw:1
+    capture_device: String,
+    #[argh(option, default = "default_channel()")]
+    /// capture channel count, defaults to 1
+    capture_channels: usize,
+    #[argh(option)]
+    /// record the capture device into this directory while playing, to verify what the speaker produced
+    loopback: Option<String>,
+    #[argh(option)]
+    /// gpio line driven high while each stimulus, or a gapless sequence, is audible
+    trigger_line: Option<u32>,
+    #[argh(option, default = "default_trigger_chip()")]
+    /// gpio chip of --trigger-line, defaults to /dev/gpiochip4
+    trigger_chip: String,
+    #[argh(option, default = "1")]
+    /// number of times to play the stimulus or sequence, defaults to 1
+    repeat: usize,
+    #[argh(switch, long = "loop")]
+    /// play the stimulus or sequence repeatedly until interrupted
+    loop_forever: bool,
+    #[argh(option, default = "0.0")]
+    /// onset and offset ramp in ms applied to each stimulus as it plays, or to the whole
+    /// sequence when played without gaps, and used to fade out when stopped, defaults to 0
+    ramp: f64,
+    #[argh(option, default = "RampShape::Cosine")]
+    /// cosine or linear, defaults to cosine
+    ramp_shape: RampShape,
+    #[argh(switch)]
+    /// remove any DC offset from stimuli as they are loaded
+    remove_dc: bool,
+    #[argh(option)]
+    /// scale each stimulus as it is loaded so its peak is at this level in dBFS
+    normalize: Option<f64>,
+    #[argh(option, short='r')]
+    /// channel routing, one row of per-file-channel weights for each device channel
+    /// separated by ';', e.g. "1;0" to play a mono file on the left speaker only
+    route: Option<String>,
+    #[argh(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Mode {
+    File(FileArgs),
+    Playlist(PlaylistArgs),
+    Synth(SynthArgs),
+    Record(RecordArgs),
+}
+
+#[derive(FromArgs)]
+/// Play a single wav file, replacing the old `playback <wav>` form
+#[argh(subcommand, name = "file")]
+struct FileArgs {
+    #[argh(positional)]
+    /// path to wav file
+    wav_file: String,
+}
+
+#[derive(FromArgs)]
+/// Preload a set of stimuli into memory and play them in sequence
+#[argh(subcommand, name = "playlist")]
+struct PlaylistArgs {
+    #[argh(positional)]
+    /// directory of wav files, or a manifest listing one path and optional weight per line
+    source: String,
+    #[argh(option, default = "Order::Ordered", short='o')]
+    /// ordered, shuffled or weighted, defaults to ordered
+    order: Order,
+    #[argh(option, default = "0", short='n')]
+    /// number of stimuli to play, defaults to one pass through the list
+    trials: usize,
+    #[argh(option, default = "default_isi()", short='i')]
+    /// inter-stimulus interval in ms, 0 plays stimuli back to back without gaps, defaults to 1000
+    isi: u64,
+}
+
+#[derive(FromArgs)]
+/// Generate and play a calibration signal
+#[argh(subcommand, name = "synth")]
+struct SynthArgs {
+    #[argh(positional)]
+    /// tone, sweep, white, pink or clicks
+    signal: SignalKind,
+    #[argh(option, default = "default_frequency()", short='f')]
+    /// tone frequency, sweep start frequency or click rate in Hz, defaults to 1000
+    frequency: f64,
+    #[argh(option, default = "default_end_frequency()", short='e')]
+    /// sweep end frequency in Hz, defaults to 8000
+    end_frequency: f64,
+    #[argh(option, default = "default_click_width()", short='w')]
+    /// click width in ms, defaults to 0.1
+    click_width: f64,
+    #[argh(option, default = "default_duration()", short='t')]
+    /// signal duration in ms, defaults to 1000
+    duration: f64,
+    #[argh(option, default = "default_level()", short='l')]
+    /// peak level in dBFS, defaults to -6
+    level: f64,
+    #[argh(option, default = "default_ramp()")]
+    /// onset and offset ramp length in ms, defaults to 5
+    ramp: f64,
+}
+
+#[derive(FromArgs)]
+/// Record from the capture device to rotating audio files
+#[argh(subcommand, name = "record")]
+struct RecordArgs {
+    #[argh(positional)]
+    /// directory to write recordings to
+    dir: String,
+    #[argh(option, default = "0", short='t')]
+    /// total length in s, 0 records until interrupted, defaults to 0
+    length: u64,
+    #[argh(option, default = "default_segment()")]
+    /// length of each file in s before starting a new one, defaults to 600
+    segment: u64,
+    #[argh(option, default = "FileFormat::Wav", short='f')]
+    /// wav or flac, defaults to wav
+    format: FileFormat,
+    #[argh(option, default = "default_prefix()")]
+    /// file name prefix, defaults to recording
+    prefix: String,
 }
 
 fn default_device() -> String {String::from("plughw:1")}
@@ -36,132 +208,250 @@ fn default_card() -> String {String::from("hw:1")}
 fn default_channel() -> usize {1}
 fn default_volume() -> i64 {100}
 fn default_rate() -> u32 {44100}
+fn default_isi() -> u64 {1000}
+fn default_frequency() -> f64 {1000.0}
+fn default_end_frequency() -> f64 {8000.0}
+fn default_click_width() -> f64 {0.1}
+fn default_duration() -> f64 {1000.0}
+fn default_level() -> f64 {-6.0}
+fn default_ramp() -> f64 {5.0}
+fn default_segment() -> u64 {600}
+fn default_prefix() -> String {String::from("recording")}
+fn default_trigger_chip() -> String {String::from("/dev/gpiochip4")}
 
 fn main() {
 
     SimpleLogger::new().init().unwrap();
-    let args: CliArgs = argh::from_env();
-    let audio_path = Path::new(&args.wav_file).canonicalize().unwrap();
-    let fname = audio_path.file_stem().unwrap().to_os_string();
-    info!("loading file {:?}", fname);
-
-    let stimulus: Vec<i16>;
-    if audio_path.extension().is_some_and(|ext| ext=="wav") {
-        let mut audio_file = sndfile::OpenOptions::ReadOnly(ReadOptions::Auto)
-            .from_path(audio_path).unwrap();
-        let wav: Vec<i16> = audio_file.read_all_to_vec().unwrap();
-        let wav_channels = audio_file.get_channels();
-        stimulus = process_audio(wav, wav_channels, args.channel);
-    } else {info!("Provided file not an audio file!"); return}
+    let mut args: CliArgs = argh::from_env();
+    if args.list {
+        devices::log_inventory(&devices::list_cards(Direction::Playback).unwrap());
+        return
+    }
+    if let Some(selector) = &args.card_id {
+        let card = match devices::find_card(selector, Direction::Playback) {
+            Ok(card) => card,
+            Err(e) => {
+                error!("Couldn't find card {}: {}. Available devices:", selector, e);
+                devices::log_inventory(&devices::list_cards(Direction::Playback).unwrap_or_default());
+                std::process::exit(1)
+            }
+        };
+        info!("using card {} ({}) at index {}", card.id, card.name, card.index);
+        args.device = card.plughw();
+        args.card = card.hw();
+    }
+    let mode = match &args.mode {
+        Some(mode) => mode,
+        None => {
+            // `playback <wav>` from before the subcommands also ends up here
+            error!("No playback mode given, a single file is now played with `playback file <wav>`, see --help");
+            std::process::exit(1)
+        }
+    };
+    if let Mode::Record(record_args) = mode {
+        let config = RecordConfig {
+            device: args.capture_device.clone(),
+            channels: args.capture_channels,
+            sample_rate: args.sample_rate,
+            format: record_args.format,
+            dir: PathBuf::from(&record_args.dir),
+            prefix: record_args.prefix.clone(),
+            segment: Duration::from_secs(record_args.segment),
+            length: if record_args.length == 0 {None} else {Some(Duration::from_secs(record_args.length))},
+        };
+        record::record(&config, &stop_on_interrupt()).unwrap();
+        return
+    }
+    let conditioning = Conditioning {
+        remove_dc: args.remove_dc,
+        normalize_dbfs: args.normalize,
+    };
+    let mut cache = StimulusCache::new(args.channel, args.sample_rate as usize, args.route.clone(), conditioning);
 
-    let audio_dev = PCM::new(&args.device.clone(), Direction::Playback, false).unwrap();
-    info!("pcm device created.");
-    get_hw_config(&audio_dev, args.channel, args.sample_rate).unwrap();
+    let sequence: Vec<String>;
+    let isi: u64;
+    match mode {
+        Mode::File(file) => {
+            let audio_path = Path::new(&file.wav_file).canonicalize().unwrap();
+            info!("loading file {:?}", audio_path.file_stem().unwrap());
+            sequence = vec![cache.preload(&audio_path).unwrap().name.clone()];
+            isi = 0;
+        },
+        Mode::Playlist(playlist_args) => {
+            let source = Path::new(&playlist_args.source);
+            let entries = if source.is_dir() {
+                cache.preload_dir(source).unwrap().into_iter().map(|name| (name, 1.0)).collect()
+            } else {
+                cache.preload_manifest(source).unwrap()
+            };
+            let playlist = Playlist::new(entries, playlist_args.order).unwrap();
+            let trials = if playlist_args.trials == 0 {playlist.len()} else {playlist_args.trials};
+            sequence = playlist.sequencer().take(trials).map(String::from).collect();
+            isi = playlist_args.isi;
+        },
+        Mode::Record(_) => unreachable!(),
+        Mode::Synth(synth_args) => {
+            let waveform = match synth_args.signal {
+                SignalKind::Tone => Waveform::Tone {frequency: synth_args.frequency},
+                SignalKind::Sweep => Waveform::Sweep {start: synth_args.frequency, end: synth_args.end_frequency},
+                SignalKind::White => Waveform::WhiteNoise,
+                SignalKind::Pink => Waveform::PinkNoise,
+                SignalKind::Clicks => Waveform::Clicks {
+                    rate: synth_args.frequency,
+                    width: synth_args.click_width / 1000.0
+                },
+            };
+            let params = SynthParams {
+                waveform,
+                duration: synth_args.duration / 1000.0,
+                level_dbfs: synth_args.level,
+                ramp: synth_args.ramp / 1000.0,
+                sample_rate: args.sample_rate as usize,
+            };
+            let stimulus = synth::generate(&params, args.channel).unwrap();
+            info!("generated {}", stimulus.name);
+            sequence = vec![cache.insert(stimulus).name.clone()];
+            isi = 0;
+        },
+    }
 
-    let mixer = alsa::mixer::Mixer::new(&args.card.clone(), false).unwrap();
-    let selem_id = alsa::mixer::SelemId::new("PCM", 0);
-    let selem = mixer.find_selem(&selem_id).ok_or_else(|| {
-        format!(
-            "Couldn't find selem with name '{}'.",
-            selem_id.get_name().unwrap_or("unnamed")
-        )
-    }).unwrap();
-    selem.set_playback_volume_range(0, 100).unwrap();
-    selem.set_playback_volume_all(args.volume).unwrap();
-    drop(mixer);
+    let hw_config = lib::HwConfig {
+        channels: args.channel,
+        sample_rate: args.sample_rate,
+        buffer_time: args.buffer_time,
+        period_time: args.period_time,
+    };
+    let volume = match args.volume_db {
+        Some(db) => Volume::Db(db),
+        None => Volume::Percent(args.volume),
+    };
+    let mut software_gain = args.gain;
+    let mut sink: Box<dyn PcmSink> = if args.null || args.output.is_some() {
+        // no mixer to set, so only a dB volume means anything and it is applied digitally
+        if let Volume::Db(db) = volume {
+            software_gain += db;
+        }
+        match &args.output {
+            Some(path) => Box::new(WavSink::new(Path::new(path), hw_config.nominal()).unwrap()),
+            None => Box::new(NullSink::new(hw_config.nominal())),
+        }
+    } else {
+        let alsa_sink = match AlsaSink::new(&args.device, &hw_config) {
+            Ok(sink) => sink,
+            Err(e) => {
+                error!("Couldn't open playback device {}: {}. Available devices:", args.device, e);
+                devices::log_inventory(&devices::list_cards(Direction::Playback).unwrap_or_default());
+                std::process::exit(1)
+            }
+        };
+        software_gain += mixer::set_volume(&args.card, args.control.as_deref(), volume).unwrap();
+        Box::new(alsa_sink)
+    };
+    match args.spl {
+        Some(target) => {
+            let calibration_path = args.calibration.as_ref()
+                .expect("A target level in dB SPL needs a --calibration file");
+            let calibration = Calibration::load(Path::new(calibration_path)).unwrap();
+            cache.apply_gain(|stimulus| calibration.gain_for(stimulus, target) + software_gain);
+        },
+        None => cache.apply_gain(|_| software_gain),
+    }
 
-    let mut io = audio_dev.io_i16().unwrap();
-    match audio_dev.prepare() {
-        Ok(n) => n,
-        Err(e) => {
-            info!("failed to prepare playback device. recovering.");
-            audio_dev.recover(e.errno() as std::os::raw::c_int, true).unwrap()
+    let loopback = args.loopback.as_ref().map(|dir| {
+        let config = RecordConfig {
+            device: args.capture_device.clone(),
+            channels: args.capture_channels,
+            sample_rate: args.sample_rate,
+            format: FileFormat::Wav,
+            dir: PathBuf::from(dir),
+            prefix: String::from("loopback"),
+            segment: Duration::from_secs(3600),
+            length: None,
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        (record::spawn(config, Arc::clone(&stop)), stop)
+    });
+    let ramp = Ramp {
+        shape: args.ramp_shape,
+        frames: (args.ramp / 1000.0 * args.sample_rate as f64).round() as usize,
+    };
+    let ramp = if ramp.frames > 0 {Some(ramp)} else {None};
+    // a stop fades out when ramping, and the fade has to be played rather than dropped
+    let discard_on_stop = ramp.is_none();
+    let trigger = args.trigger_line.map(|line| Trigger::new(&args.trigger_chip, line).unwrap());
+    let stop = stop_on_interrupt();
+    let block = if isi == 0 {
+        cache.concat(sequence.iter().map(String::as_str)).unwrap()
+    } else {
+        Vec::new()
+    };
+    let mut repetition = 0;
+    while !stop.load(Ordering::Relaxed) && (args.loop_forever || repetition < args.repeat) {
+        repetition += 1;
+        if isi == 0 {
+            // back to back, so only drained once everything has been queued
+            info!("staring playback of {} stimuli, repetition {}", sequence.len(), repetition);
+            let stats = lib::playback_io(sink.as_mut(), &block, &stop, ramp.as_ref(),
+                                         trigger.as_ref()).unwrap();
+            info!("sequence: {}", stats);
+        } else {
+            let last_repetition = !args.loop_forever && repetition == args.repeat;
+            for (trial, name) in sequence.iter().enumerate() {
+                if stop.load(Ordering::Relaxed) {
+                    break
+                }
+                info!("trial {}: playing {}", trial + 1, name);
+                let stats = lib::playback_io(sink.as_mut(), &cache.get(name).unwrap().samples, &stop,
+                                             ramp.as_ref(), trigger.as_ref()).unwrap();
+                info!("{}: {}", name, stats);
+                sink.finish(stats.stopped && discard_on_stop).unwrap();
+                if stats.stopped {
+                    break
+                }
+                if last_repetition && trial + 1 == sequence.len() {
+                    break
+                }
+                if !sleep_or_stop(Duration::from_millis(isi), &stop) {
+                    break
+                }
+            }
         }
     }
-    info!("staring playback");
-    let _ = playback_io(&audio_dev, &mut io, &stimulus);
+    sink.finish(stop.load(Ordering::Relaxed) && discard_on_stop).unwrap();
+    // sets the last offset edge before exiting
+    drop(trigger);
+    if let Some((recorder, stop)) = loopback {
+        // playback has drained, leave time for the tail to come back through the capture device
+        std::thread::sleep(Duration::from_millis(200));
+        stop.store(true, Ordering::Relaxed);
+        let files = recorder.join().unwrap().unwrap();
+        info!("loopback recorded to {:?}", files);
+    }
     info!("complete!")
 }
 
-
-fn process_audio(wav: Vec<i16>, wav_channels: usize, hw_channels: usize) -> Vec<i16> {
-    let mut result = Vec::new();
-    if wav_channels == 1 {
-        result = wav;
-        if hw_channels == 2 {
-            result = result.into_iter()
-                .map(|note| [note, note])
-                .flatten()
-                .map(|f| f )
-                .collect::<Vec<i16>>()
+/// Sleep for `duration`, returning false as soon as `stop` is set instead.
+fn sleep_or_stop(duration: Duration, stop: &AtomicBool) -> bool {
+    const POLL: Duration = Duration::from_millis(10);
+    let until = std::time::Instant::now() + duration;
+    while !stop.load(Ordering::Relaxed) {
+        let left = until.saturating_duration_since(std::time::Instant::now());
+        if left.is_zero() {
+            return true
         }
-    } else if wav_channels == 2 {
-        result = wav;
-        if hw_channels == 1 {
-            result = result.into_iter()
-                .enumerate()
-                .filter(|f| f.0.clone() % 2 == 0)
-                .map(|f| f.1)
-                .collect::<Vec<_>>()
-        }
-    };
-    result
+        std::thread::sleep(left.min(POLL));
+    }
+    false
 }
 
-
-fn get_hw_config<'a>(pcm: &'a PCM, channel: usize, sampling_rate: u32) -> Result<bool, String>{
-    let hwp = HwParams::any(&pcm).unwrap();
-    hwp.set_channels(channel as u32).unwrap();
-    hwp.set_rate(sampling_rate, alsa::ValueOr::Nearest).unwrap();
-    hwp.set_access(Access::RWInterleaved).unwrap();
-    hwp.set_format(Format::s16()).unwrap();
-    hwp.set_buffer_size(1024).unwrap();
-    // hwp.set_period_size(512, alsa::ValueOr::Nearest).unwrap();
-    pcm.hw_params(&hwp).unwrap();
-    Ok(true)
+/// Flag that is set once the process receives Ctrl-C.
+fn stop_on_interrupt() -> Arc<AtomicBool> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_signal = Arc::clone(&stop);
+    std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(tokio::signal::ctrl_c()).unwrap();
+        info!("interrupted, stopping");
+        stop_signal.store(true, Ordering::Relaxed);
+    });
+    stop
 }
-
-fn playback_io(pcm: &PCM, io: &mut alsa::pcm::IO<i16>, data: &Vec<i16>)
-                   -> Result<bool, String> {
-    let frames: usize = data.len();
-    let _avail = match pcm.avail_update() {
-        Ok(n) => n,
-        Err(e) => {
-            info!("sound-alsa failed to call available update, recovering from {}", e);
-            pcm.recover(e.errno() as std::os::raw::c_int, true).unwrap();
-            pcm.avail_update().unwrap()
-        }
-    } as usize;
-    let mut pointer = 0;
-    let mut _written: usize = 0;
-    //loop while playing
-    while pointer < frames-1 {
-        let slice = if pointer+512>frames {&data[pointer..]} else {&data[pointer..pointer+512]};
-        _written = match io.writei(slice) {
-            Ok(n) => n,
-            Err(e) => {
-                info!("Recovering from {}", e);
-                pcm.recover(e.errno() as std::os::raw::c_int, true).unwrap();
-                0
-            }
-        };
-        pointer += _written;
-        match pcm.state() {
-            State::Running => {
-            }, // All fine
-            State::Prepared => {
-                pcm.start().unwrap();
-            },
-            State::XRun => {
-                info!("underrun in audio output stream!, will call prepare()");
-                pcm.prepare().unwrap();
-            },
-            State::Suspended => {
-                info!("sound-alsa suspended, will call prepare()");
-                pcm.prepare().unwrap();
-            },
-            n @ _ => panic!("sound-alsa unexpected pcm state {:?}", n),
-        };
-    };
-    Ok(true)
-}
\ No newline at end of file
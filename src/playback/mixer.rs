@@ -0,0 +1,121 @@
+use alsa::mixer::{Mixer, MilliBel, Selem, SelemId};
+use alsa::Round;
+use log::{info, warn};
+use crate::lib::Error;
+
+/// Control names tried in order when none is configured.
+const PREFERRED_CONTROLS: [&str; 5] = ["PCM", "Master", "Speaker", "Headphone", "Digital"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Volume {
+    /// Fraction of the control's native range, 0 to 100
+    Percent(i64),
+    /// Absolute attenuation or gain in dB
+    Db(f64),
+}
+
+#[derive(Clone, Debug)]
+pub struct MixerControl {
+    pub name: String,
+    pub index: u32,
+    pub volume_range: (i64, i64),
+    /// (min, max) in dB, if the driver reports a dB scale
+    pub db_range: Option<(f64, f64)>,
+}
+
+impl MixerControl {
+    fn from_selem(selem: &Selem) -> Option<Self> {
+        if !selem.has_playback_volume() {
+            return None
+        }
+        let id = selem.get_id();
+        let (db_min, db_max) = selem.get_playback_db_range();
+        let db_range = if db_max.0 > db_min.0 {
+            Some((db_min.to_db() as f64, db_max.to_db() as f64))
+        } else {
+            None
+        };
+        Some(MixerControl {
+            name: id.get_name().unwrap_or("unnamed").to_string(),
+            index: id.get_index(),
+            volume_range: selem.get_playback_volume_range(),
+            db_range,
+        })
+    }
+}
+
+/// Playback volume controls exposed by a card.
+pub fn list_controls(mixer: &Mixer) -> Vec<MixerControl> {
+    mixer.iter()
+        .filter_map(Selem::new)
+        .filter_map(|selem| MixerControl::from_selem(&selem))
+        .collect()
+}
+
+/// Pick the configured control, or the first of the usual names the card exposes,
+/// or failing that whatever playback control comes first.
+fn choose_control<'a>(controls: &'a [MixerControl], name: Option<&str>) -> Option<&'a MixerControl> {
+    match name {
+        Some(name) => controls.iter().find(|c| c.name == name),
+        None => PREFERRED_CONTROLS.iter()
+            .find_map(|preferred| controls.iter().find(|c| c.name == *preferred))
+            .or(controls.first()),
+    }
+}
+
+/// Set the playback volume on `card`. Returns the gain in dB that still has to be
+/// applied in software, which is non-zero when a dB volume was asked for but the
+/// control has no dB scale.
+pub fn set_volume(card: &str, control: Option<&str>, volume: Volume) -> Result<f64, Error> {
+    let mixer_err = |e| Error::Mixer {source: e, card: card.to_string()};
+    let mixer = Mixer::new(card, false).map_err(mixer_err)?;
+    let controls = list_controls(&mixer);
+    for c in &controls {
+        info!("mixer control '{}',{} range {:?} dB {:?}", c.name, c.index, c.volume_range, c.db_range);
+    }
+    let chosen = choose_control(&controls, control).ok_or_else(|| Error::NoMixerControl {
+        card: card.to_string(),
+        name: control.unwrap_or("any").to_string(),
+    })?;
+    info!("using mixer control '{}'", chosen.name);
+    let selem = mixer.find_selem(&SelemId::new(&chosen.name, chosen.index)).unwrap();
+
+    match volume {
+        Volume::Percent(percent) => {
+            let (min, max) = chosen.volume_range;
+            let raw = min + (max - min) * percent.clamp(0, 100) / 100;
+            selem.set_playback_volume_all(raw).map_err(mixer_err)?;
+            Ok(0.0)
+        },
+        Volume::Db(db) => match chosen.db_range {
+            Some((min, max)) => {
+                if db < min || db > max {
+                    warn!("{}dB outside of control range {}dB to {}dB, clamping", db, min, max);
+                }
+                let db = db.clamp(min, max);
+                selem.set_playback_db_all(MilliBel::from_db(db as f32), Round::Floor).map_err(mixer_err)?;
+                info!("mixer set to {}dB", db);
+                Ok(0.0)
+            },
+            None => {
+                warn!("mixer control '{}' has no dB scale, applying {}dB in software", chosen.name, db);
+                Ok(db)
+            },
+        },
+    }
+}
+
+/// Scale samples by `db`, clamping anything pushed past full scale.
+/// Returns the number of samples that clipped.
+pub fn apply_gain(samples: &mut [i16], db: f64) -> usize {
+    let gain = 10f64.powf(db / 20.0);
+    let mut clipped = 0;
+    for sample in samples.iter_mut() {
+        let scaled = (*sample as f64 * gain).round();
+        if scaled > i16::MAX as f64 || scaled < i16::MIN as f64 {
+            clipped += 1;
+        }
+        *sample = scaled.clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+    }
+    clipped
+}
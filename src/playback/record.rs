@@ -0,0 +1,145 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::Duration;
+use alsa::{Direction, PCM};
+use alsa::pcm::State;
+use chrono::Local;
+use log::{info, warn};
+use sndfile::{self, Endian, MajorFormat, SndFile, SndFileIO, SubtypeFormat, WriteOptions};
+use crate::lib::{self, Error};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileFormat {
+    Wav,
+    Flac,
+}
+
+impl FromStr for FileFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wav" => Ok(FileFormat::Wav),
+            "flac" => Ok(FileFormat::Flac),
+            _ => Err(format!("unknown recording format '{}', expected wav or flac", s)),
+        }
+    }
+}
+
+impl FileFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            FileFormat::Wav => "wav",
+            FileFormat::Flac => "flac",
+        }
+    }
+    fn major(&self) -> MajorFormat {
+        match self {
+            FileFormat::Wav => MajorFormat::WAV,
+            FileFormat::Flac => MajorFormat::FLAC,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RecordConfig {
+    pub device: String,
+    pub channels: usize,
+    pub sample_rate: u32,
+    pub format: FileFormat,
+    pub dir: PathBuf,
+    /// file names are `<prefix>_<start time>.<ext>`
+    pub prefix: String,
+    /// start a new file after this much audio
+    pub segment: Duration,
+    /// stop after this much audio, or only when asked to if `None`
+    pub length: Option<Duration>,
+}
+
+impl RecordConfig {
+    fn frames(&self, duration: Duration) -> usize {
+        (duration.as_secs_f64() * self.sample_rate as f64).round() as usize
+    }
+
+    fn create_file(&self) -> Result<(SndFile, PathBuf), Error> {
+        let stamp = Local::now().format("%Y%m%d-%H%M%S%.3f");
+        let path = self.dir.join(format!("{}_{}.{}", self.prefix, stamp, self.format.extension()));
+        let options = WriteOptions::new(self.format.major(), SubtypeFormat::PCM_16, Endian::File,
+                                        self.sample_rate as usize, self.channels);
+        let file = sndfile::OpenOptions::WriteOnly(options)
+            .from_path(&path)
+            .map_err(|_| Error::FileCreate {path: path.display().to_string()})?;
+        info!("recording to {:?}", path);
+        Ok((file, path))
+    }
+}
+
+/// Record from the capture device until `stop` is set or the configured length is reached.
+/// Returns the files written, in order.
+pub fn record(config: &RecordConfig, stop: &AtomicBool) -> Result<Vec<PathBuf>, Error> {
+    let capture_err = |e| Error::Capture {source: e, device: config.device.clone()};
+    let pcm = PCM::new(&config.device, Direction::Capture, false).map_err(capture_err)?;
+    let hw_config = lib::HwConfig {
+        channels: config.channels,
+        sample_rate: config.sample_rate,
+        buffer_time: None,
+        period_time: None,
+    };
+    let settings = lib::get_hw_config(&pcm, &hw_config)?;
+    let io = pcm.io_i16().map_err(capture_err)?;
+    pcm.prepare().map_err(capture_err)?;
+    pcm.start().map_err(capture_err)?;
+
+    let segment_frames = config.frames(config.segment).max(1);
+    let total_frames = config.length.map(|length| config.frames(length));
+    let mut buffer = vec![0i16; settings.period_size * config.channels];
+    let mut files = Vec::new();
+    let (mut file, path) = config.create_file()?;
+    files.push(path);
+    let mut segment_written: usize = 0;
+    let mut total_written: usize = 0;
+    let mut overruns: usize = 0;
+
+    while !stop.load(Ordering::Relaxed) && total_frames.map_or(true, |total| total_written < total) {
+        let frames = match io.readi(&mut buffer) {
+            Ok(n) => n,
+            Err(e) => {
+                overruns += 1;
+                warn!("overrun in audio capture stream, recovering from {}", e);
+                pcm.recover(e.errno() as std::os::raw::c_int, true).map_err(capture_err)?;
+                if pcm.state() == State::Prepared {
+                    pcm.start().map_err(capture_err)?;
+                }
+                continue
+            }
+        };
+        let frames = total_frames.map_or(frames, |total| frames.min(total - total_written));
+        let mut offset = 0;
+        while offset < frames {
+            if segment_written == segment_frames {
+                let (next, path) = config.create_file()?;
+                file = next;
+                files.push(path);
+                segment_written = 0;
+            }
+            let take = (frames - offset).min(segment_frames - segment_written);
+            let chunk = &buffer[offset * config.channels..(offset + take) * config.channels];
+            file.write_from_slice(chunk)
+                .map_err(|_| Error::FileWrite {path: files[files.len() - 1].display().to_string()})?;
+            offset += take;
+            segment_written += take;
+        }
+        total_written += frames;
+    }
+    let _ = pcm.drop();
+    info!("recorded {:.1}s to {} files, {} overruns",
+        total_written as f64 / config.sample_rate as f64, files.len(), overruns);
+    Ok(files)
+}
+
+/// Record on a separate thread, e.g. alongside playback, until `stop` is set.
+pub fn spawn(config: RecordConfig, stop: Arc<AtomicBool>) -> thread::JoinHandle<Result<Vec<PathBuf>, Error>> {
+    thread::spawn(move || record(&config, &stop))
+}
+
@@ -0,0 +1,95 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use rand::{rngs::ThreadRng, seq::SliceRandom, distributions::{Distribution, WeightedIndex}};
+use crate::lib::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    /// Play entries in list order, wrapping around at the end
+    Ordered,
+    /// Play every entry once per block, reshuffling each block
+    Shuffled,
+    /// Draw each trial independently with probability proportional to weight
+    Weighted,
+}
+
+impl FromStr for Order {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ordered" => Ok(Order::Ordered),
+            "shuffled" => Ok(Order::Shuffled),
+            "weighted" => Ok(Order::Weighted),
+            _ => Err(format!("unknown playlist order '{}', expected ordered, shuffled or weighted", s)),
+        }
+    }
+}
+
+pub struct Playlist {
+    entries: Vec<(String, f64)>,
+    order: Order,
+}
+
+impl Playlist {
+    pub fn new(entries: Vec<(String, f64)>, order: Order) -> Result<Self, Error> {
+        if entries.is_empty() {
+            return Err(Error::Playlist {reason: String::from("no stimuli")})
+        }
+        if entries.iter().any(|(_, weight)| !(*weight >= 0.0)) {
+            return Err(Error::Playlist {reason: String::from("weights must be non-negative")})
+        }
+        if order == Order::Weighted && entries.iter().all(|(_, weight)| *weight == 0.0) {
+            return Err(Error::Playlist {reason: String::from("all weights are zero")})
+        }
+        Ok(Playlist {
+            entries,
+            order,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn sequencer(&self) -> Sequencer<'_> {
+        let weighted = match self.order {
+            Order::Weighted => Some(WeightedIndex::new(self.entries.iter().map(|(_, w)| *w)).unwrap()),
+            _ => None,
+        };
+        Sequencer {
+            playlist: self,
+            queue: VecDeque::new(),
+            rng: rand::thread_rng(),
+            weighted,
+        }
+    }
+}
+
+/// Endless stream of stimulus names drawn from a playlist.
+pub struct Sequencer<'a> {
+    playlist: &'a Playlist,
+    queue: VecDeque<usize>,
+    rng: ThreadRng,
+    weighted: Option<WeightedIndex<f64>>,
+}
+
+impl<'a> Iterator for Sequencer<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = match &self.weighted {
+            Some(dist) => dist.sample(&mut self.rng),
+            None => {
+                if self.queue.is_empty() {
+                    let mut block: Vec<usize> = (0..self.playlist.len()).collect();
+                    if self.playlist.order == Order::Shuffled {
+                        block.shuffle(&mut self.rng);
+                    }
+                    self.queue.extend(block);
+                }
+                self.queue.pop_front().unwrap()
+            }
+        };
+        Some(self.playlist.entries[index].0.as_str())
+    }
+}
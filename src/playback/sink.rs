@@ -0,0 +1,299 @@
+use std::os::raw::c_int;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use alsa::{Direction, PCM};
+use alsa::pcm::State;
+use log::{debug, info};
+use sndfile::{self, Endian, MajorFormat, SndFile, SndFileIO, SubtypeFormat, WriteOptions};
+use crate::lib::{self, Error, HwConfig, HwSettings, PlaybackStats};
+
+/// Destination for interleaved i16 audio written by `playback_io`.
+pub trait PcmSink {
+    /// Layout and chunking of the audio the sink expects.
+    fn settings(&self) -> HwSettings;
+
+    /// Called once before each stimulus is written.
+    fn begin(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Write interleaved samples, returning the number of frames accepted.
+    fn write(&mut self, samples: &[i16], stats: &mut PlaybackStats) -> Result<usize, Error>;
+
+    /// Frames queued ahead of the next write, once output is running.
+    fn delay(&self) -> Option<i64> {
+        Some(0)
+    }
+
+    /// Let queued audio play out, or discard it if `stopped`.
+    fn finish(&mut self, stopped: bool) -> Result<(), Error>;
+}
+
+pub struct AlsaSink {
+    pcm: PCM,
+    device: String,
+    settings: HwSettings,
+}
+
+impl AlsaSink {
+    pub fn new(device: &str, config: &HwConfig) -> Result<Self, Error> {
+        let playback_err = |e| Error::Playback {source: e, device: device.to_string()};
+        let pcm = PCM::new(device, Direction::Playback, false).map_err(playback_err)?;
+        info!("pcm device created.");
+        let settings = lib::get_hw_config(&pcm, config)?;
+        match pcm.prepare() {
+            Ok(n) => n,
+            Err(e) => {
+                info!("failed to prepare playback device. recovering.");
+                pcm.recover(e.errno() as c_int, true).map_err(playback_err)?
+            }
+        }
+        Ok(AlsaSink {
+            pcm,
+            device: device.to_string(),
+            settings,
+        })
+    }
+
+    fn playback_err(&self, e: alsa::Error) -> Error {
+        Error::Playback {source: e, device: self.device.clone()}
+    }
+}
+
+impl PcmSink for AlsaSink {
+    fn settings(&self) -> HwSettings {
+        self.settings
+    }
+
+    fn begin(&mut self) -> Result<(), Error> {
+        let avail = match self.pcm.avail_update() {
+            Ok(n) => n,
+            Err(e) => {
+                info!("sound-alsa failed to call available update, recovering from {}", e);
+                self.pcm.recover(e.errno() as c_int, true).map_err(|e| self.playback_err(e))?;
+                self.pcm.avail_update().map_err(|e| self.playback_err(e))?
+            }
+        } as usize;
+        debug!("{} frames free in playback buffer", avail);
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[i16], stats: &mut PlaybackStats) -> Result<usize, Error> {
+        let io = self.pcm.io_i16().map_err(|e| self.playback_err(e))?;
+        let written = match io.writei(samples) {
+            Ok(n) => n,
+            Err(e) => {
+                if self.pcm.state() == State::XRun {
+                    stats.underruns += 1;
+                }
+                info!("Recovering from {}", e);
+                self.pcm.recover(e.errno() as c_int, true).map_err(|e| self.playback_err(e))?;
+                0
+            }
+        };
+        match self.pcm.state() {
+            State::Running => {
+            }, // All fine
+            State::Prepared => {
+                self.pcm.start().map_err(|e| self.playback_err(e))?;
+            },
+            State::XRun => {
+                stats.underruns += 1;
+                info!("underrun in audio output stream!, will call prepare()");
+                self.pcm.prepare().map_err(|e| self.playback_err(e))?;
+            },
+            State::Suspended => {
+                info!("sound-alsa suspended, will call prepare()");
+                self.pcm.prepare().map_err(|e| self.playback_err(e))?;
+            },
+            n @ _ => panic!("sound-alsa unexpected pcm state {:?}", n),
+        };
+        Ok(written)
+    }
+
+    fn delay(&self) -> Option<i64> {
+        if self.pcm.state() == State::Running { self.pcm.delay().ok() } else { None }
+    }
+
+    fn finish(&mut self, stopped: bool) -> Result<(), Error> {
+        let result = if stopped { self.pcm.drop() } else { self.pcm.drain() };
+        if let Err(e) = result {
+            info!("failed to drain playback device, recovering from {}", e);
+            self.pcm.recover(e.errno() as c_int, true).map_err(|e| self.playback_err(e))?;
+        }
+        self.pcm.prepare().map_err(|e| self.playback_err(e))
+    }
+}
+
+/// Renders audio into a 16-bit wav file instead of playing it.
+pub struct WavSink {
+    file: SndFile,
+    path: String,
+    settings: HwSettings,
+}
+
+impl WavSink {
+    pub fn new(path: &Path, settings: HwSettings) -> Result<Self, Error> {
+        let display = path.display().to_string();
+        let options = WriteOptions::new(MajorFormat::WAV, SubtypeFormat::PCM_16, Endian::File,
+                                        settings.sample_rate as usize, settings.channels);
+        let file = sndfile::OpenOptions::WriteOnly(options)
+            .from_path(path)
+            .map_err(|_| Error::FileCreate {path: display.clone()})?;
+        info!("rendering to {}", display);
+        Ok(WavSink {
+            file,
+            path: display,
+            settings,
+        })
+    }
+}
+
+impl PcmSink for WavSink {
+    fn settings(&self) -> HwSettings {
+        self.settings
+    }
+
+    fn write(&mut self, samples: &[i16], _stats: &mut PlaybackStats) -> Result<usize, Error> {
+        self.file.write_from_slice(samples)
+            .map_err(|_| Error::FileWrite {path: self.path.clone()})?;
+        Ok(samples.len() / self.settings.channels)
+    }
+
+    fn finish(&mut self, _stopped: bool) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Discards audio, keeping the time and size of every chunk written.
+pub struct NullSink {
+    settings: HwSettings,
+    started: Instant,
+    chunks: Vec<(Duration, usize)>,
+}
+
+impl NullSink {
+    pub fn new(settings: HwSettings) -> Self {
+        NullSink {
+            settings,
+            started: Instant::now(),
+            chunks: Vec::new(),
+        }
+    }
+}
+
+impl PcmSink for NullSink {
+    fn settings(&self) -> HwSettings {
+        self.settings
+    }
+
+    fn write(&mut self, samples: &[i16], _stats: &mut PlaybackStats) -> Result<usize, Error> {
+        let frames = samples.len() / self.settings.channels;
+        let at = self.started.elapsed();
+        debug!("null sink chunk of {} frames at {:?}", frames, at);
+        self.chunks.push((at, frames));
+        Ok(frames)
+    }
+
+    fn finish(&mut self, _stopped: bool) -> Result<(), Error> {
+        let frames: usize = self.chunks.iter().map(|(_, frames)| frames).sum();
+        let span = self.chunks.last().map(|(at, _)| *at).unwrap_or_default();
+        info!("null sink received {} frames in {} chunks over {:?}", frames, self.chunks.len(), span);
+        self.chunks.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use super::*;
+    use crate::lib::{playback_io, process_audio, ChannelMap, Conditioning, Ramp, RampShape};
+
+    /// Keeps every sample written, in order.
+    struct MemorySink {
+        settings: HwSettings,
+        samples: Vec<i16>,
+    }
+
+    impl MemorySink {
+        fn new(channels: usize, period_size: usize) -> Self {
+            MemorySink {
+                settings: HwSettings {
+                    channels,
+                    sample_rate: 44_100,
+                    buffer_size: period_size * 4,
+                    period_size,
+                },
+                samples: Vec::new(),
+            }
+        }
+    }
+
+    impl PcmSink for MemorySink {
+        fn settings(&self) -> HwSettings {
+            self.settings
+        }
+
+        fn write(&mut self, samples: &[i16], _stats: &mut PlaybackStats) -> Result<usize, Error> {
+            self.samples.extend_from_slice(samples);
+            Ok(samples.len() / self.settings.channels)
+        }
+
+        fn finish(&mut self, _stopped: bool) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// A mono tone with a DC offset, long enough to span many periods.
+    fn stimulus() -> Vec<i16> {
+        (0..5_000)
+            .map(|n| (1_000.0 + 20_000.0 * (n as f64 * 0.05).sin()).round() as i16)
+            .collect()
+    }
+
+    fn rendered(ramp: Option<&Ramp>) -> (Vec<i16>, Vec<i16>) {
+        let map = ChannelMap::default_for(1, 2).unwrap();
+        let conditioning = Conditioning {remove_dc: true, normalize_dbfs: Some(-6.0)};
+        let expected = process_audio(stimulus(), &map, &conditioning).unwrap();
+        let mut sink = MemorySink::new(2, 256);
+        let stats = playback_io(&mut sink, &expected, &AtomicBool::new(false), ramp, None).unwrap();
+        assert_eq!(stats.frames_written, expected.len() / 2);
+        assert!(!stats.stopped);
+        (expected, sink.samples)
+    }
+
+    #[test]
+    fn renders_bit_for_bit() {
+        let (expected, samples) = rendered(None);
+        assert_eq!(samples, expected);
+    }
+
+    #[test]
+    fn stop_before_start_writes_nothing() {
+        let map = ChannelMap::default_for(1, 2).unwrap();
+        let data = process_audio(stimulus(), &map, &Conditioning::default()).unwrap();
+        let ramp = Ramp {shape: RampShape::Cosine, frames: 441};
+        for ramp in [None, Some(&ramp)] {
+            let mut sink = MemorySink::new(2, 256);
+            let stats = playback_io(&mut sink, &data, &AtomicBool::new(true), ramp, None).unwrap();
+            assert!(stats.stopped);
+            assert_eq!(stats.frames_written, 0);
+            assert!(sink.samples.is_empty());
+        }
+    }
+
+    #[test]
+    fn ramps_start_and_end_at_zero() {
+        for shape in [RampShape::Cosine, RampShape::Linear] {
+            let ramp = Ramp {shape, frames: 441};
+            let (expected, samples) = rendered(Some(&ramp));
+            assert_eq!(samples.len(), expected.len());
+            assert_eq!(samples[..2], [0, 0]);
+            assert_eq!(samples[samples.len() - 2..], [0, 0]);
+            // untouched between the ramps
+            let middle = 441 * 2..expected.len() - 441 * 2;
+            assert_eq!(samples[middle.clone()], expected[middle]);
+        }
+    }
+}
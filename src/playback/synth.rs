@@ -0,0 +1,155 @@
+use std::f64::consts::PI;
+use std::str::FromStr;
+use rand::Rng;
+use crate::lib::{self, ChannelMap, Conditioning, Error, Ramp, RampShape, Stimulus};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    /// Pure sine tone at a fixed frequency in Hz
+    Tone { frequency: f64 },
+    /// Logarithmic sine sweep between two frequencies in Hz
+    Sweep { start: f64, end: f64 },
+    WhiteNoise,
+    PinkNoise,
+    /// Rectangular clicks of `width` seconds repeated at `rate` Hz
+    Clicks { rate: f64, width: f64 },
+}
+
+/// Signal kinds as named on the command line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SignalKind {
+    Tone,
+    Sweep,
+    White,
+    Pink,
+    Clicks,
+}
+
+impl FromStr for SignalKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tone" => Ok(SignalKind::Tone),
+            "sweep" => Ok(SignalKind::Sweep),
+            "white" => Ok(SignalKind::White),
+            "pink" => Ok(SignalKind::Pink),
+            "clicks" => Ok(SignalKind::Clicks),
+            _ => Err(format!("unknown signal '{}', expected tone, sweep, white, pink or clicks", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SynthParams {
+    pub waveform: Waveform,
+    /// length in seconds
+    pub duration: f64,
+    /// peak level relative to full scale
+    pub level_dbfs: f64,
+    /// raised-cosine onset and offset ramp length in seconds
+    pub ramp: f64,
+    pub sample_rate: usize,
+}
+
+impl SynthParams {
+    fn name(&self) -> String {
+        let signal = match self.waveform {
+            Waveform::Tone {frequency} => format!("tone_{}Hz", frequency),
+            Waveform::Sweep {start, end} => format!("sweep_{}-{}Hz", start, end),
+            Waveform::WhiteNoise => String::from("white"),
+            Waveform::PinkNoise => String::from("pink"),
+            Waveform::Clicks {rate, ..} => format!("clicks_{}Hz", rate),
+        };
+        format!("{}_{}ms_{}dBFS", signal, (self.duration * 1000.0).round(), self.level_dbfs)
+    }
+}
+
+/// Generate a mono signal in the range -1.0 to 1.0, before level and ramps are applied.
+fn waveform(params: &SynthParams) -> Vec<f64> {
+    let rate = params.sample_rate as f64;
+    let frames = (params.duration * rate).round() as usize;
+    let mut rng = rand::thread_rng();
+    match params.waveform {
+        Waveform::Tone {frequency} => (0..frames)
+            .map(|n| (2.0 * PI * frequency * n as f64 / rate).sin())
+            .collect(),
+        Waveform::Sweep {start, end} => {
+            // phase of an exponential chirp, so each octave takes the same time
+            let k = (end / start).ln() / params.duration;
+            (0..frames)
+                .map(|n| {
+                    let t = n as f64 / rate;
+                    let phase = if k == 0.0 {start * t} else {start * ((k * t).exp() - 1.0) / k};
+                    (2.0 * PI * phase).sin()
+                })
+                .collect()
+        },
+        Waveform::WhiteNoise => (0..frames).map(|_| rng.gen_range(-1.0..=1.0)).collect(),
+        Waveform::PinkNoise => {
+            // Paul Kellet's economy filter, -3dB/octave above ~10Hz
+            let (mut b0, mut b1, mut b2) = (0.0, 0.0, 0.0);
+            let pink: Vec<f64> = (0..frames)
+                .map(|_| {
+                    let white: f64 = rng.gen_range(-1.0..=1.0);
+                    b0 = 0.99765 * b0 + white * 0.0990460;
+                    b1 = 0.96300 * b1 + white * 0.2965164;
+                    b2 = 0.57000 * b2 + white * 1.0526913;
+                    b0 + b1 + b2 + white * 0.1848
+                })
+                .collect();
+            let peak = pink.iter().fold(0.0_f64, |max, x| max.max(x.abs()));
+            if peak > 0.0 { pink.into_iter().map(|x| x / peak).collect() } else { pink }
+        },
+        Waveform::Clicks {rate: click_rate, width} => {
+            let period = (rate / click_rate).round().max(1.0) as usize;
+            let click = (width * rate).round().max(1.0) as usize;
+            (0..frames).map(|n| if n % period < click {1.0} else {0.0}).collect()
+        },
+    }
+}
+
+fn validate(params: &SynthParams) -> Result<(), String> {
+    let nyquist = params.sample_rate as f64 / 2.0;
+    let audible = |f: f64| f > 0.0 && f < nyquist;
+    if !(params.duration > 0.0) {
+        return Err(String::from("duration must be positive"))
+    }
+    if params.level_dbfs > 0.0 {
+        return Err(format!("level of {}dBFS would clip", params.level_dbfs))
+    }
+    if !(params.ramp >= 0.0) {
+        return Err(String::from("ramp must not be negative"))
+    }
+    match params.waveform {
+        Waveform::Tone {frequency} if !audible(frequency) =>
+            Err(format!("tone frequency must be between 0 and {}Hz", nyquist)),
+        Waveform::Sweep {start, end} if !audible(start) || !audible(end) =>
+            Err(format!("sweep frequencies must be between 0 and {}Hz", nyquist)),
+        Waveform::Clicks {rate, width} if !(rate > 0.0 && width > 0.0 && width * rate < 1.0) =>
+            Err(String::from("clicks need a positive rate and a width shorter than the click period")),
+        _ => Ok(()),
+    }
+}
+
+pub fn generate(params: &SynthParams, channels: usize) -> Result<Stimulus, Error> {
+    validate(params).map_err(|reason| Error::Synth {reason})?;
+    let signal = waveform(params);
+    let frames = signal.len();
+    let amplitude = 10f64.powf(params.level_dbfs / 20.0) * i16::MAX as f64;
+    let ramp = Ramp {
+        shape: RampShape::Cosine,
+        frames: ((params.ramp * params.sample_rate as f64).round() as usize).min(frames / 2),
+    };
+    let mono: Vec<i16> = signal.into_iter()
+        .enumerate()
+        .map(|(n, x)| {
+            let sample = x * amplitude * ramp.gain(n.min(frames - 1 - n));
+            sample.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+        })
+        .collect();
+    Ok(Stimulus {
+        name: params.name(),
+        samples: lib::process_audio(mono, &ChannelMap::default_for(1, channels)?, &Conditioning::default())?,
+        sample_rate: params.sample_rate,
+    })
+}
@@ -0,0 +1,71 @@
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Instant;
+use chrono::Local;
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags, errors::Error as GpioError};
+use log::{info, warn};
+use crate::lib::Error;
+
+/// TTL output raised while a stimulus is audible, for aligning recordings with playback.
+///
+/// Edges are scheduled ahead of time from the PCM delay and set by a worker thread,
+/// so writing audio never waits on them. Every edge is logged with when it was
+/// actually set and how late that was against the estimated sample time.
+pub struct Trigger {
+    sender: Option<Sender<(Instant, bool)>>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl Trigger {
+    pub fn new(chip: &str, line: u32) -> Result<Self, Error> {
+        let trigger_err = |e: GpioError| Error::Trigger {source: e, chip: chip.to_string(), line};
+        let handle = Chip::new(chip).map_err(trigger_err)?
+            .get_line(line).map_err(trigger_err)?
+            .request(LineRequestFlags::OUTPUT, 0, "playback_trigger")
+            .map_err(trigger_err)?;
+        info!("trigger output on {} line {}", chip, line);
+        let (sender, receiver) = mpsc::channel();
+        let worker = thread::spawn(move || {
+            for (at, high) in receiver {
+                set_at(&handle, at, high);
+            }
+            // leave the line low however playback ended
+            let _ = handle.set_value(0);
+        });
+        Ok(Trigger {
+            sender: Some(sender),
+            worker: Some(worker),
+        })
+    }
+
+    /// Drive the line high or low at `at`, or straight away if that has passed.
+    pub fn schedule(&self, at: Instant, high: bool) {
+        if let Some(sender) = &self.sender {
+            sender.send((at, high)).unwrap();
+        }
+    }
+}
+
+fn set_at(handle: &LineHandle, at: Instant, high: bool) {
+    let now = Instant::now();
+    if at > now {
+        thread::sleep(at - now);
+    }
+    if let Err(e) = handle.set_value(high as u8) {
+        warn!("failed to set trigger {}: {}", if high {"high"} else {"low"}, e);
+        return
+    }
+    let late = Instant::now().saturating_duration_since(at);
+    info!("trigger {} at {} ({:.3}ms after the sample)",
+        if high {"high"} else {"low"}, Local::now().format("%H:%M:%S%.6f"), late.as_secs_f64() * 1000.0);
+}
+
+impl Drop for Trigger {
+    /// Waits for edges still scheduled, so the last offset is not lost on exit.
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use i2cdev::core::I2CDevice;
+use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
+use log::info;
+use thiserror;
+
+/// Fixed address of the VCNL4040.
+pub const ADDRESS: u16 = 0x60;
+// Registers from the VCNL4040 datasheet, each a little endian word read and written over SMBus.
+/// PS_CONF1 in the low byte, PS_CONF2 in the high byte
+const PS_CONF1_2: u8 = 0x03;
+const PS_THDL: u8 = 0x06;
+const PS_THDH: u8 = 0x07;
+/// INT_FLAG in the high byte, cleared by reading it
+const INT_FLAG: u8 = 0x0B;
+
+/// PS_PERS, bits 5:4 of PS_CONF1
+const PERSISTENCE_SHIFT: u16 = 4;
+const PERSISTENCE_MASK: u16 = 0b11 << PERSISTENCE_SHIFT;
+/// PS_INT, bits 1:0 of PS_CONF2, set to interrupt on both close and away
+const INTERRUPT_MASK: u16 = 0b11 << 8;
+const INTERRUPT_CLOSE_AND_AWAY: u16 = 0b11 << 8;
+/// PS_IF_CLOSE and PS_IF_AWAY in INT_FLAG
+const FLAG_CLOSE: u16 = 1 << 9;
+const FLAG_AWAY: u16 = 1 << 8;
+
+/// Which proximity thresholds were crossed since the flags were last read.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Flags {
+    pub close: bool,
+    pub away: bool,
+}
+
+/// The sensor's proximity threshold interrupt, configured and read through its own handle on the bus.
+pub struct ProximityInterrupt {
+    device: LinuxI2CDevice,
+}
+
+impl ProximityInterrupt {
+    /// Raise INT when proximity rises above `high` or falls below `low` for `persistence`
+    /// consecutive readings, between 1 and 4. Call once the driver has finished configuring
+    /// the sensor, as its setters rewrite PS_CONF1 and PS_CONF2 whole.
+    pub fn new(bus: &str, low: u16, high: u16, persistence: u8) -> Result<Self, Error> {
+        let config_err = |e| Error::ConfigError {source: e};
+        let mut device = LinuxI2CDevice::new(bus, ADDRESS).map_err(config_err)?;
+        device.smbus_write_word_data(PS_THDL, low).map_err(config_err)?;
+        device.smbus_write_word_data(PS_THDH, high).map_err(config_err)?;
+        let conf = device.smbus_read_word_data(PS_CONF1_2).map_err(config_err)?;
+        let persistence = (persistence.clamp(1, 4) as u16 - 1) << PERSISTENCE_SHIFT;
+        let conf = (conf & !(PERSISTENCE_MASK | INTERRUPT_MASK)) | persistence | INTERRUPT_CLOSE_AND_AWAY;
+        device.smbus_write_word_data(PS_CONF1_2, conf).map_err(config_err)?;
+        info!("Proximity interrupt between {} and {}, PS_CONF {:#06x}", low, high, conf);
+        let mut interrupt = ProximityInterrupt {device};
+        // INT is held low until the flags are read, so clear anything raised before now
+        interrupt.flags()?;
+        Ok(interrupt)
+    }
+
+    /// Read and clear the interrupt flags, releasing INT.
+    pub fn flags(&mut self) -> Result<Flags, Error> {
+        let flags = self.device.smbus_read_word_data(INT_FLAG).map_err(|e| Error::FlagError {source: e})?;
+        Ok(Flags {
+            close: flags & FLAG_CLOSE != 0,
+            away: flags & FLAG_AWAY != 0,
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed to configure the proximity interrupt")]
+    ConfigError {
+        source: LinuxI2CError,
+    },
+    #[error("Failed to read the interrupt flags")]
+    FlagError {
+        source: LinuxI2CError,
+    },
+}
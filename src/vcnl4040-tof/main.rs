@@ -1,29 +1,173 @@
-// Example usage (commented out since it requires specific hardware)
+mod interrupt;
 use vcnl4040::{LedCurrent, LedDutyCycle, ProximityIntegrationTime, Vcnl4040};
-use linux_embedded_hal_async::{i2c};
+use linux_embedded_hal_async::{digital, i2c};
+use embedded_hal_async::digital::Wait;
 use i2cdev::linux::LinuxI2CBus;
-// use argh::{self, FromArgs};
+use argh::{self, FromArgs};
 use simple_logger::SimpleLogger;
-use log::info;
-use std::time::Duration;
+use log::{error, info, warn};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::RecvError};
+use interrupt::ProximityInterrupt;
+
+#[derive(FromArgs)]
+/// Get proximity readings from the VCNL4040 sensor
+struct CliArgs {
+    /// i2c bus the sensor is on, defaults to /dev/i2c-2
+    #[argh(option, default = "default_bus()")]
+    bus: String,
+    /// IR LED current in mA, one of 50, 75, 100, 120, 140, 160, 180 or 200, defaults to 100
+    #[argh(option, default = "LedCurrent::Current100mA", from_str_fn(parse_led_current))]
+    led_current: LedCurrent,
+    /// IR LED duty cycle as 1/N, N one of 40, 80, 160 or 320, defaults to 160
+    #[argh(option, default = "LedDutyCycle::Duty1_160", from_str_fn(parse_duty_cycle))]
+    duty_cycle: LedDutyCycle,
+    /// proximity integration time in T, one of 1, 1.5, 2, 2.5, 3, 3.5, 4 or 8, defaults to 2
+    #[argh(option, default = "ProximityIntegrationTime::Time2T", from_str_fn(parse_integration_time))]
+    integration_time: ProximityIntegrationTime,
+    /// interval between readings in ms when polling, defaults to 50
+    #[argh(option, default = "default_interval()")]
+    interval: u64,
+    /// gpio line wired to the sensor's INT pin. Waits for close/away threshold interrupts
+    /// instead of polling if given
+    #[argh(option)]
+    interrupt_line: Option<u32>,
+    /// gpio chip of --interrupt-line, defaults to /dev/gpiochip2
+    #[argh(option, default = "default_gpio_chip()")]
+    interrupt_chip: String,
+    /// proximity count above which something is close, defaults to 1000
+    #[argh(option, default = "default_high_threshold()")]
+    high_threshold: u16,
+    /// proximity count below which it is away again, defaults to 800
+    #[argh(option, default = "default_low_threshold()")]
+    low_threshold: u16,
+    /// consecutive readings past a threshold before the interrupt fires, one of 1 to 4, defaults to 1
+    #[argh(option, default = "default_persistence()", from_str_fn(parse_persistence))]
+    persistence: u8,
+}
+fn default_bus() -> String {String::from("/dev/i2c-2")}
+fn default_interval() -> u64 {50}
+fn default_gpio_chip() -> String {String::from("/dev/gpiochip2")}
+fn default_high_threshold() -> u16 {1000}
+fn default_low_threshold() -> u16 {800}
+fn default_persistence() -> u8 {1}
+
+fn parse_led_current(value: &str) -> Result<LedCurrent, String> {
+    match value {
+        "50" => Ok(LedCurrent::Current50mA),
+        "75" => Ok(LedCurrent::Current75mA),
+        "100" => Ok(LedCurrent::Current100mA),
+        "120" => Ok(LedCurrent::Current120mA),
+        "140" => Ok(LedCurrent::Current140mA),
+        "160" => Ok(LedCurrent::Current160mA),
+        "180" => Ok(LedCurrent::Current180mA),
+        "200" => Ok(LedCurrent::Current200mA),
+        _ => Err(format!("'{}' is not a supported LED current", value)),
+    }
+}
+
+fn parse_duty_cycle(value: &str) -> Result<LedDutyCycle, String> {
+    match value {
+        "40" => Ok(LedDutyCycle::Duty1_40),
+        "80" => Ok(LedDutyCycle::Duty1_80),
+        "160" => Ok(LedDutyCycle::Duty1_160),
+        "320" => Ok(LedDutyCycle::Duty1_320),
+        _ => Err(format!("'{}' is not a supported duty cycle", value)),
+    }
+}
+
+fn parse_integration_time(value: &str) -> Result<ProximityIntegrationTime, String> {
+    match value {
+        "1" => Ok(ProximityIntegrationTime::Time1T),
+        "1.5" => Ok(ProximityIntegrationTime::Time1_5T),
+        "2" => Ok(ProximityIntegrationTime::Time2T),
+        "2.5" => Ok(ProximityIntegrationTime::Time2_5T),
+        "3" => Ok(ProximityIntegrationTime::Time3T),
+        "3.5" => Ok(ProximityIntegrationTime::Time3_5T),
+        "4" => Ok(ProximityIntegrationTime::Time4T),
+        "8" => Ok(ProximityIntegrationTime::Time8T),
+        _ => Err(format!("'{}' is not a supported integration time", value)),
+    }
+}
+
+fn parse_persistence(value: &str) -> Result<u8, String> {
+    value.parse().ok()
+        .filter(|persistence| (1..=4).contains(persistence))
+        .ok_or_else(|| format!("'{}' is not a persistence between 1 and 4", value))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Event {
+    /// proximity rose past the high threshold
+    Close { proximity: u16, at: Instant },
+    /// proximity fell past the low threshold
+    Away { proximity: u16, at: Instant },
+}
+
+/// Log proximity events as they arrive, until every sender is gone.
+async fn log_events(mut events: broadcast::Receiver<Event>) {
+    loop {
+        match events.recv().await {
+            Ok(Event::Close {proximity, ..}) => info!("Close at proximity {}", proximity),
+            Ok(Event::Away {proximity, ..}) => info!("Away at proximity {}", proximity),
+            Err(RecvError::Lagged(missed)) => warn!("Logger skipped {} proximity events", missed),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
     SimpleLogger::new().init().unwrap();
+    let args: CliArgs = argh::from_env();
+    if args.low_threshold >= args.high_threshold {
+        error!("--low-threshold {} must be below --high-threshold {}", args.low_threshold, args.high_threshold);
+        std::process::exit(1)
+    }
     let dev = i2c::LinuxI2c::new(
-        LinuxI2CBus::new("/dev/i2c-2").unwrap()
+        LinuxI2CBus::new(&args.bus).unwrap()
     );
 
     let mut sensor = Vcnl4040::new(dev);
     sensor.init(false).await.unwrap();
     sensor.enable_proximity(true).await.unwrap();
-    sensor.set_proximity_led_current(LedCurrent::Current100mA).await.unwrap();
-    sensor.set_proximity_led_duty_cycle(LedDutyCycle::Duty1_160).await.unwrap();
-    sensor.set_proximity_integration_time(ProximityIntegrationTime::Time2T).await.unwrap();
+    sensor.set_proximity_led_current(args.led_current).await.unwrap();
+    sensor.set_proximity_led_duty_cycle(args.duty_cycle).await.unwrap();
+    sensor.set_proximity_integration_time(args.integration_time).await.unwrap();
+
+    let line = match args.interrupt_line {
+        Some(line) => line,
+        None => loop {
+            let dist = sensor.get_proximity().await.unwrap();
+            info!("distance is {}", dist);
+            tokio::time::sleep(Duration::from_millis(args.interval)).await;
+        },
+    };
+
+    // configured outside the driver, and after it, since its setters rewrite the whole config register
+    let mut thresholds = ProximityInterrupt::new(&args.bus, args.low_threshold, args.high_threshold, args.persistence)
+        .expect("Couldn't configure the proximity interrupt");
+    let mut int = digital::CdevPin::new(&args.interrupt_chip, line, "vcnl4040")
+        .expect("Couldn't request the sensor interrupt line");
+    info!("Waiting for proximity interrupts on {} line {}", args.interrupt_chip, line);
 
+    let (events, _) = broadcast::channel::<Event>(16);
+    tokio::spawn(log_events(events.subscribe()));
     loop {
-        let dist = sensor.get_proximity().await.unwrap();
-        info!("distance is {}", dist);
-        tokio::time::sleep(Duration::from_millis(50)).await;
+        // INT stays low until the flags are read, so this returns at once for an interrupt
+        // raised while the previous one was being handled
+        int.wait_for_low().await.unwrap();
+        let flags = thresholds.flags().unwrap();
+        let proximity = sensor.get_proximity().await.unwrap();
+        let at = Instant::now();
+        let crossed = [
+            (flags.close, Event::Close {proximity, at}),
+            (flags.away, Event::Away {proximity, at}),
+        ];
+        for (_, event) in crossed.into_iter().filter(|(flagged, _)| *flagged) {
+            if events.send(event).is_err() {
+                warn!("No one is listening for proximity events, dropped {:?}", event);
+            }
+        }
     }
 }
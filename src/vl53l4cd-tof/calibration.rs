@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use i2cdev::core::I2CDevice;
+use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
+use log::info;
+use thiserror;
+use vl53l4cd::Measurement;
+
+// Registers as named in ST's VL53L4CD ultra lite driver. All are big endian words except the ROI bytes.
+const XTALK_PLANE_OFFSET_KCPS: u16 = 0x0016;
+const XTALK_X_PLANE_GRADIENT_KCPS: u16 = 0x0018;
+const XTALK_Y_PLANE_GRADIENT_KCPS: u16 = 0x001A;
+const RANGE_OFFSET_MM: u16 = 0x001E;
+const INNER_OFFSET_MM: u16 = 0x0020;
+const OUTER_OFFSET_MM: u16 = 0x0022;
+const RANGE_CONFIG_SIGMA_THRESH: u16 = 0x0064;
+const MIN_COUNT_RATE_RTN_LIMIT_MCPS: u16 = 0x0066;
+const ROI_CONFIG_USER_ROI_CENTRE_SPAD: u16 = 0x007F;
+const ROI_CONFIG_USER_ROI_REQUESTED_GLOBAL_XY_SIZE: u16 = 0x0080;
+
+/// Measurements discarded while the sensor settles before calibrating.
+pub const WARM_UP_SAMPLES: usize = 10;
+
+/// Region of the 16x16 SPAD array used for ranging.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Roi {
+    /// SPAD number at the centre, 199 is the middle of the array
+    pub centre: u8,
+    pub width: u8,
+    pub height: u8,
+}
+
+/// Corrections for one sensor's mounting, applied after the driver's `init`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Calibration {
+    /// added to every distance, from `offset_for`
+    pub offset_mm: i16,
+    /// crosstalk from a cover glass, from `xtalk_for`
+    pub xtalk_kcps: u16,
+    pub roi: Option<Roi>,
+    /// readings with more uncertainty than this are flagged invalid
+    pub sigma_mm: Option<u16>,
+    /// readings with less return signal than this are flagged invalid
+    pub signal_kcps: Option<u16>,
+}
+
+/// Calibrations for every sensor, kept in a file of `<sensor> <key> <value>` lines:
+///
+/// ```text
+/// left offset_mm 12
+/// left xtalk_kcps 4
+/// left roi 199 8x8
+/// left sigma_mm 15
+/// left signal_kcps 1024
+/// ```
+pub fn load(path: &Path) -> Result<BTreeMap<String, Calibration>, Error> {
+    let display = path.display().to_string();
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        // nothing calibrated yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(Error::ReadError {source: e, path: display}),
+    };
+    let parse_err = |line: &str| Error::ParseError {path: display.clone(), line: line.to_string()};
+    let mut calibrations: BTreeMap<String, Calibration> = BTreeMap::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (sensor, key, values) = match fields.as_slice() {
+            [sensor, key, values @ ..] if !values.is_empty() => (sensor, key, values),
+            _ => return Err(parse_err(line)),
+        };
+        let calibration = calibrations.entry(sensor.to_string()).or_default();
+        match (*key, values) {
+            ("offset_mm", [value]) => calibration.offset_mm = value.parse().map_err(|_| parse_err(line))?,
+            ("xtalk_kcps", [value]) => calibration.xtalk_kcps = value.parse().map_err(|_| parse_err(line))?,
+            ("sigma_mm", [value]) => calibration.sigma_mm = Some(value.parse().map_err(|_| parse_err(line))?),
+            ("signal_kcps", [value]) => calibration.signal_kcps = Some(value.parse().map_err(|_| parse_err(line))?),
+            ("roi", [centre, size]) => calibration.roi = Some(parse_roi(centre, size).ok_or_else(|| parse_err(line))?),
+            _ => return Err(parse_err(line)),
+        }
+    }
+    Ok(calibrations)
+}
+
+pub fn save(path: &Path, calibrations: &BTreeMap<String, Calibration>) -> Result<(), Error> {
+    let mut contents = String::new();
+    for (sensor, calibration) in calibrations {
+        contents += &format!("{} offset_mm {}\n", sensor, calibration.offset_mm);
+        contents += &format!("{} xtalk_kcps {}\n", sensor, calibration.xtalk_kcps);
+        if let Some(roi) = calibration.roi {
+            contents += &format!("{} roi {} {}x{}\n", sensor, roi.centre, roi.width, roi.height);
+        }
+        if let Some(sigma) = calibration.sigma_mm {
+            contents += &format!("{} sigma_mm {}\n", sensor, sigma);
+        }
+        if let Some(signal) = calibration.signal_kcps {
+            contents += &format!("{} signal_kcps {}\n", sensor, signal);
+        }
+    }
+    fs::write(path, contents).map_err(|e| Error::WriteError {source: e, path: path.display().to_string()})?;
+    info!("Calibration saved to {:?}", path);
+    Ok(())
+}
+
+/// ROI from a centre SPAD and a "<width>x<height>" size, each between 4 and 16.
+pub fn parse_roi(centre: &str, size: &str) -> Option<Roi> {
+    let (width, height) = size.split_once('x')?;
+    let roi = Roi {
+        centre: centre.parse().ok()?,
+        width: width.parse().ok()?,
+        height: height.parse().ok()?,
+    };
+    let valid = |side: u8| (4..=16).contains(&side);
+    if valid(roi.width) && valid(roi.height) {Some(roi)} else {None}
+}
+
+fn write_word(device: &mut LinuxI2CDevice, register: u16, value: u16) -> Result<(), LinuxI2CError> {
+    let [reg_hi, reg_lo] = register.to_be_bytes();
+    let [hi, lo] = value.to_be_bytes();
+    device.write(&[reg_hi, reg_lo, hi, lo])
+}
+
+fn write_byte(device: &mut LinuxI2CDevice, register: u16, value: u8) -> Result<(), LinuxI2CError> {
+    let [reg_hi, reg_lo] = register.to_be_bytes();
+    device.write(&[reg_hi, reg_lo, value])
+}
+
+impl Calibration {
+    /// Write the calibration to the sensor at `address`. Call after `init`, which resets
+    /// the thresholds and ROI to their defaults, and before `start_ranging`.
+    pub fn apply(&self, bus: &str, address: u8, sensor: &str) -> Result<(), Error> {
+        let calibration_err = |e| Error::ApplyError {source: e, sensor: sensor.to_string()};
+        let mut device = LinuxI2CDevice::new(bus, address as u16).map_err(calibration_err)?;
+        // the offset register holds quarter millimetres
+        write_word(&mut device, RANGE_OFFSET_MM, (self.offset_mm.wrapping_mul(4)) as u16).map_err(calibration_err)?;
+        write_word(&mut device, INNER_OFFSET_MM, 0).map_err(calibration_err)?;
+        write_word(&mut device, OUTER_OFFSET_MM, 0).map_err(calibration_err)?;
+        write_word(&mut device, XTALK_X_PLANE_GRADIENT_KCPS, 0).map_err(calibration_err)?;
+        write_word(&mut device, XTALK_Y_PLANE_GRADIENT_KCPS, 0).map_err(calibration_err)?;
+        // crosstalk is held in 7.9 fixed point mcps
+        let xtalk = ((self.xtalk_kcps as u32) << 9) / 1000;
+        write_word(&mut device, XTALK_PLANE_OFFSET_KCPS, xtalk as u16).map_err(calibration_err)?;
+        if let Some(sigma) = self.sigma_mm {
+            write_word(&mut device, RANGE_CONFIG_SIGMA_THRESH, sigma.min(16383) << 2).map_err(calibration_err)?;
+        }
+        if let Some(signal) = self.signal_kcps {
+            write_word(&mut device, MIN_COUNT_RATE_RTN_LIMIT_MCPS, signal >> 3).map_err(calibration_err)?;
+        }
+        if let Some(roi) = self.roi {
+            write_byte(&mut device, ROI_CONFIG_USER_ROI_CENTRE_SPAD, roi.centre).map_err(calibration_err)?;
+            write_byte(&mut device, ROI_CONFIG_USER_ROI_REQUESTED_GLOBAL_XY_SIZE,
+                       (roi.height - 1) << 4 | (roi.width - 1)).map_err(calibration_err)?;
+        }
+        info!("{}: applied {:?}", sensor, self);
+        Ok(())
+    }
+}
+
+/// Offset that corrects readings of a target at `target_mm`, from measurements
+/// taken with no offset applied.
+pub fn offset_for(samples: &[Measurement], target_mm: u16) -> Option<i16> {
+    let valid: Vec<&Measurement> = samples.iter().filter(|m| m.is_valid()).collect();
+    if valid.is_empty() {
+        return None
+    }
+    let average = valid.iter().map(|m| m.distance as f64).sum::<f64>() / valid.len() as f64;
+    Some((target_mm as f64 - average).round() as i16)
+}
+
+/// Crosstalk in kcps from measurements of a target at `target_mm` behind the cover glass,
+/// taken with no crosstalk correction applied.
+pub fn xtalk_for(samples: &[Measurement], target_mm: u16) -> Option<u16> {
+    let valid: Vec<&Measurement> = samples.iter().filter(|m| m.is_valid()).collect();
+    if valid.is_empty() {
+        return None
+    }
+    let count = valid.len() as f64;
+    let distance = valid.iter().map(|m| m.distance as f64).sum::<f64>() / count;
+    let signal = valid.iter().map(|m| m.signal_rate as f64).sum::<f64>() / count;
+    let spads = valid.iter().map(|m| m.spads_enabled as f64).sum::<f64>() / count;
+    if spads == 0.0 {
+        return None
+    }
+    let xtalk = signal / spads * (1.0 - distance / target_mm as f64);
+    Some(xtalk.max(0.0).round() as u16)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed to read calibration file {path}")]
+    ReadError {
+        source: std::io::Error,
+        path: String,
+    },
+    #[error("Invalid line '{line}' in calibration file {path}")]
+    ParseError {
+        path: String,
+        line: String,
+    },
+    #[error("Failed to write calibration file {path}")]
+    WriteError {
+        source: std::io::Error,
+        path: String,
+    },
+    #[error("Failed to apply calibration to sensor {sensor}")]
+    ApplyError {
+        source: LinuxI2CError,
+        sensor: String,
+    },
+}
\ No newline at end of file
@@ -0,0 +1,115 @@
+use std::{
+    error,
+    fmt::{self, Display, Formatter},
+};
+
+use embedded_hal_async::digital::{self, ErrorKind, ErrorType, Wait};
+use futures::StreamExt;
+use gpio_cdev::{
+    errors::Error as GpioError, AsyncLineEventHandle, Chip, EventRequestFlags, EventType,
+    LineRequestFlags,
+};
+
+#[derive(Debug)]
+pub enum CdevPinError {
+    Gpio(GpioError),
+    /// The kernel stopped delivering events for the line.
+    Closed,
+}
+
+impl digital::Error for CdevPinError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+impl From<GpioError> for CdevPinError {
+    fn from(value: GpioError) -> Self {
+        Self::Gpio(value)
+    }
+}
+
+impl Display for CdevPinError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Gpio(err) => write!(f, "Linux GPIO error: {}", err),
+            Self::Closed => write!(f, "Linux GPIO event stream closed"),
+        }
+    }
+}
+
+impl error::Error for CdevPinError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Gpio(err) => Some(err),
+            Self::Closed => None,
+        }
+    }
+}
+
+/// Input line that waits for edges with gpio-cdev line events instead of polling.
+///
+/// Edges are queued by the kernel from the moment the line is requested, so none are missed
+/// between waits, but an edge that happened before a wait started can also end an edge wait.
+/// Level waits check the line again after every edge, so a stale edge never ends them early.
+pub struct CdevPin(AsyncLineEventHandle);
+
+impl CdevPin {
+    pub fn new(chip: &str, line: u32, consumer: &str) -> Result<Self, CdevPinError> {
+        let events = Chip::new(chip)?
+            .get_line(line)?
+            .events(LineRequestFlags::INPUT, EventRequestFlags::BOTH_EDGES, consumer)?;
+        Ok(Self(AsyncLineEventHandle::new(events)?))
+    }
+
+    fn value(&self) -> Result<u8, CdevPinError> {
+        Ok(self.0.as_ref().get_value()?)
+    }
+
+    async fn next_edge(&mut self) -> Result<EventType, CdevPinError> {
+        match self.0.next().await {
+            Some(event) => Ok(event?.event_type()),
+            None => Err(CdevPinError::Closed),
+        }
+    }
+
+    async fn wait_for_edge(&mut self, edge: EventType) -> Result<(), CdevPinError> {
+        while self.next_edge().await? != edge {}
+        Ok(())
+    }
+
+    async fn wait_for_level(&mut self, level: u8) -> Result<(), CdevPinError> {
+        loop {
+            if self.value()? == level {
+                return Ok(());
+            }
+            self.next_edge().await?;
+        }
+    }
+}
+
+impl ErrorType for CdevPin {
+    type Error = CdevPinError;
+}
+
+impl Wait for CdevPin {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(1).await
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(0).await
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_edge(EventType::RisingEdge).await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_edge(EventType::FallingEdge).await
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        self.next_edge().await.map(|_| ())
+    }
+}
\ No newline at end of file
@@ -58,11 +58,17 @@ impl error::Error for LinuxI2cError {
     }
 }
 
-pub struct LinuxI2c(LinuxI2CBus);
+pub struct LinuxI2c(LinuxI2CBus, Option<u16>);
 
 impl LinuxI2c {
     pub fn new(bus: LinuxI2CBus) -> Self {
-        Self(bus)
+        Self(bus, None)
+    }
+
+    /// Send every transaction to `address`, whatever address the driver asks for. Lets drivers
+    /// that only know a device's default address talk to one that has been moved.
+    pub fn with_address(bus: LinuxI2CBus, address: u16) -> Self {
+        Self(bus, Some(address))
     }
 
     fn transact(
@@ -71,6 +77,7 @@ impl LinuxI2c {
         operations: &mut [Operation<'_>],
         _flags: I2CMessageFlags,
     ) -> Result<(), LinuxI2cError> {
+        let address = self.1.unwrap_or(address);
         let (_, mut vec) = TokioScope::scope_and_block(|scope| {
             scope.spawn_blocking(|| {
                 let mut msgs: Box<[LinuxI2CMessage]> = operations
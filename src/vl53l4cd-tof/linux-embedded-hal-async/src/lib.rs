@@ -1,4 +1,5 @@
 #![allow(incomplete_features)]
 
 pub mod delay;
+pub mod digital;
 pub mod i2c;
\ No newline at end of file
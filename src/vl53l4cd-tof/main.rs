@@ -1,9 +1,18 @@
+mod calibration;
+mod sensors;
+mod tripwire;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
 use vl53l4cd::Vl53l4cd;
 use i2cdev::linux::LinuxI2CBus;
-use linux_embedded_hal_async::{delay, i2c};
+use linux_embedded_hal_async::{delay, digital, i2c};
 use argh::{self, FromArgs};
 use simple_logger::SimpleLogger;
-use log::info;
+use log::{info, warn};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tripwire::{Event, Tripwire, TripwireConfig};
+use calibration::Calibration;
 
 #[derive(FromArgs)]
 /// Get distance readings from the ToF sensor
@@ -14,32 +23,262 @@ struct CliArgs {
     /// interval between readings, must be lower than timing budget, defaults to 0
     #[argh(option, default = "default_interval()")]
     interval: u32,
+    /// distance in mm below which the tripwire is entered, defaults to 100
+    #[argh(option, default = "default_threshold()")]
+    threshold: u16,
+    /// extra distance in mm beyond the threshold needed to exit, defaults to 20
+    #[argh(option, default = "default_hysteresis()")]
+    hysteresis: u16,
+    /// consecutive readings past the threshold before an event, defaults to 3
+    #[argh(option, default = "default_dwell()")]
+    dwell: usize,
+    /// i2c bus the sensors are on, defaults to /dev/i2c-2
+    #[argh(option, default = "default_bus()")]
+    bus: String,
+    /// gpio line wired to a sensor's XSHUT pin, once per sensor when several share the bus
+    #[argh(option)]
+    xshut_line: Vec<u32>,
+    /// gpio chip of the XSHUT lines, defaults to /dev/gpiochip2
+    #[argh(option, default = "default_gpio_chip()")]
+    xshut_chip: String,
+    /// name of each sensor in --xshut-line order, used in logs and events, defaults to its index
+    #[argh(option)]
+    name: Vec<String>,
+    /// i2c address given to the first of several sensors, the rest count up from it. Only the last
+    /// may land on the sensors' default address 0x29. Defaults to 0x30
+    #[argh(option, default = "default_first_address()", from_str_fn(parse_address))]
+    first_address: u8,
+    /// gpio line wired to a sensor's GPIO1 interrupt output, once per sensor in --xshut-line order,
+    /// polls over i2c if not given
+    #[argh(option)]
+    interrupt_line: Vec<u32>,
+    /// gpio chip of --interrupt-line, defaults to /dev/gpiochip2
+    #[argh(option, default = "default_gpio_chip()")]
+    interrupt_chip: String,
+    /// log every distance reading as well as tripwire events
+    #[argh(switch)]
+    raw: bool,
+    /// file holding each sensor's calibration, applied at startup and written by --calibrate-offset
+    /// and --calibrate-xtalk
+    #[argh(option)]
+    calibration: Option<String>,
+    /// measure the offset with a target at this distance in mm, save it and exit
+    #[argh(option)]
+    calibrate_offset: Option<u16>,
+    /// measure the cover glass crosstalk with a target at this distance in mm, save it and exit.
+    /// Run after the offset calibration
+    #[argh(option)]
+    calibrate_xtalk: Option<u16>,
+    /// measurements averaged per calibration, defaults to 20
+    #[argh(option, default = "default_calibration_samples()")]
+    calibration_samples: usize,
+    /// SPAD at the centre of the region of interest, 199 is the middle of the array
+    #[argh(option)]
+    roi_centre: Option<u8>,
+    /// size of the region of interest as <width>x<height> SPADs, each between 4 and 16
+    #[argh(option)]
+    roi_size: Option<String>,
+    /// readings with a sigma above this in mm are flagged invalid
+    #[argh(option)]
+    sigma: Option<u16>,
+    /// readings with a return signal below this in kcps are flagged invalid
+    #[argh(option)]
+    signal: Option<u16>,
 }
 fn default_budget() -> u32 {50}
 fn default_interval() -> u32 {0}
+fn default_threshold() -> u16 {100}
+fn default_hysteresis() -> u16 {20}
+fn default_dwell() -> usize {3}
+fn default_bus() -> String {String::from("/dev/i2c-2")}
+fn default_gpio_chip() -> String {String::from("/dev/gpiochip2")}
+fn default_first_address() -> u8 {0x30}
+fn default_calibration_samples() -> usize {20}
 
-#[tokio::main]
-async fn main() {
-    SimpleLogger::new().init().unwrap();
-    let args: CliArgs = argh::from_env();
-    let dev = i2c::LinuxI2c::new(
-        LinuxI2CBus::new("/dev/i2c-2").unwrap()
-    );
-    let mut sensor = Vl53l4cd::new(
-        dev,
-        delay::LinuxDelay,
-        vl53l4cd::wait::Poll,
-    );
-    // sensor.set_range_timing(30, 30).await.unwrap();
+fn parse_address(value: &str) -> Result<u8, String> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.ok()
+        .filter(|address| (0x08..0x78).contains(address))
+        .ok_or_else(|| format!("'{}' is not a 7-bit i2c address", value))
+}
+
+/// The calibration saved for `name`, with the ROI and thresholds given on the command line in place
+/// of the saved ones.
+fn calibration_for(name: &str, saved: &BTreeMap<String, Calibration>, args: &CliArgs) -> Calibration {
+    let mut calibration = saved.get(name).copied().unwrap_or_default();
+    if args.roi_centre.is_some() || args.roi_size.is_some() {
+        let current = calibration.roi.unwrap_or(calibration::Roi {centre: 199, width: 16, height: 16});
+        let centre = args.roi_centre.unwrap_or(current.centre).to_string();
+        let size = args.roi_size.clone().unwrap_or_else(|| format!("{}x{}", current.width, current.height));
+        calibration.roi = Some(calibration::parse_roi(&centre, &size)
+            .expect("--roi-size must be <width>x<height> with each side between 4 and 16"));
+    }
+    calibration.sigma_mm = args.sigma.or(calibration.sigma_mm);
+    calibration.signal_kcps = args.signal.or(calibration.signal_kcps);
+    calibration
+}
+
+/// Range with a target at a known distance and work out the offset and crosstalk that were asked for,
+/// returning `calibration` updated with them. The offset is found first, as the crosstalk depends on it.
+/// Whatever is not recalibrated keeps its saved value.
+async fn calibrate(name: &str, dev: i2c::LinuxI2c, address: u8, mut calibration: Calibration,
+                   args: &CliArgs) -> Calibration {
+    let mut sensor = Vl53l4cd::new(dev, delay::LinuxDelay, vl53l4cd::wait::Poll);
     sensor.init().await.unwrap();
     sensor.set_range_timing(args.budget, args.interval).await.unwrap();
-    sensor.start_ranging().await.unwrap();
-    loop {
-        let measure = sensor.measure().await.unwrap();
-        if !measure.is_valid() {
-            info!("Measurement not valid {:?}", measure)
+    let targets = [(args.calibrate_offset, true), (args.calibrate_xtalk, false)];
+    for (target, offset) in targets.into_iter().filter_map(|(target, offset)| Some((target?, offset))) {
+        // only the value being measured is cleared, so a saved crosstalk survives an offset recalibration
+        if offset {
+            calibration.offset_mm = 0;
+            if calibration.xtalk_kcps != 0 {
+                info!("{}: Keeping the saved crosstalk of {}kcps while measuring the offset",
+                    name, calibration.xtalk_kcps);
+            }
         } else {
-            info!("Distance is {}", measure.distance)
+            calibration.xtalk_kcps = 0;
+        }
+        calibration.apply(&args.bus, address, name).expect("Couldn't apply the calibration");
+        sensor.start_ranging().await.unwrap();
+        let mut samples = Vec::with_capacity(args.calibration_samples);
+        for index in 0..calibration::WARM_UP_SAMPLES + args.calibration_samples {
+            let measure = sensor.measure().await.unwrap();
+            if index >= calibration::WARM_UP_SAMPLES {
+                samples.push(measure);
+            }
+        }
+        sensor.stop_ranging().await.unwrap();
+        if offset {
+            calibration.offset_mm = calibration::offset_for(&samples, target)
+                .expect("No valid measurements of the offset target");
+            info!("{}: Offset is {}mm", name, calibration.offset_mm);
+        } else {
+            calibration.xtalk_kcps = calibration::xtalk_for(&samples, target)
+                .expect("No valid measurements of the crosstalk target");
+            info!("{}: Crosstalk is {}kcps", name, calibration.xtalk_kcps);
+        }
+    }
+    calibration
+}
+
+/// Start ranging and feed every measurement to the tripwire. A macro rather than a function
+/// since the sensor's type depends on the wait strategy chosen at runtime.
+macro_rules! range {
+    ($name:expr, $sensor:expr, $address:expr, $calibration:expr, $args:expr, $tripwire:expr, $events:expr) => {{
+        let mut sensor = $sensor;
+        // sensor.set_range_timing(30, 30).await.unwrap();
+        sensor.init().await.unwrap();
+        $calibration.apply(&$args.bus, $address, &$name).expect("Couldn't apply the calibration");
+        sensor.set_range_timing($args.budget, $args.interval).await.unwrap();
+        sensor.start_ranging().await.unwrap();
+        loop {
+            let measure = sensor.measure().await.unwrap();
+            if $args.raw {
+                if !measure.is_valid() {
+                    info!("{}: Measurement not valid {:?}", $name, measure)
+                } else {
+                    info!("{}: Distance is {}", $name, measure.distance)
+                }
+            }
+            if let Some(event) = $tripwire.feed(&measure) {
+                // only fails when nobody is subscribed
+                let _ = $events.send(($name.clone(), event));
+            }
         }
+    }};
+}
+
+/// Range with one sensor until the process exits, sending its tripwire events tagged with `name`.
+async fn run_sensor(name: String, dev: i2c::LinuxI2c, address: u8, calibration: Calibration,
+                    interrupt_line: Option<u32>, args: Arc<CliArgs>, events: broadcast::Sender<(String, Event)>) {
+    let mut tripwire = Tripwire::new(TripwireConfig {
+        threshold: args.threshold,
+        hysteresis: args.hysteresis,
+        dwell: args.dwell,
+    });
+    match interrupt_line {
+        Some(line) => {
+            let gpio1 = digital::CdevPin::new(&args.interrupt_chip, line, "vl53l4cd")
+                .expect("Couldn't request the sensor interrupt line");
+            info!("{}: Waiting for data ready on {} line {}", name, args.interrupt_chip, line);
+            range!(name, Vl53l4cd::new(dev, delay::LinuxDelay, vl53l4cd::wait::Interrupt(gpio1)),
+                address, calibration, args, tripwire, events)
+        },
+        None => range!(name, Vl53l4cd::new(dev, delay::LinuxDelay, vl53l4cd::wait::Poll),
+            address, calibration, args, tripwire, events),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    SimpleLogger::new().init().unwrap();
+    let args: Arc<CliArgs> = Arc::new(argh::from_env());
+    let calibrating = args.calibrate_offset.is_some() || args.calibrate_xtalk.is_some();
+    let mut saved = match &args.calibration {
+        Some(path) => calibration::load(Path::new(path)).expect("Couldn't load the calibration"),
+        None if calibrating => panic!("--calibration is needed to save the calibration to"),
+        None => BTreeMap::new(),
+    };
+    let (events, _) = broadcast::channel::<(String, Event)>(16);
+    let mut subscriber = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match subscriber.recv().await {
+                Ok((name, Event::Entered {distance, ..})) => info!("{}: Tripwire entered at {}mm", name, distance),
+                Ok((name, Event::Exited {distance, ..})) => info!("{}: Tripwire exited at {}mm", name, distance),
+                Err(RecvError::Lagged(missed)) => warn!("Fell behind, {} tripwire events not logged", missed),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    if args.xshut_line.is_empty() {
+        // a single sensor left at its default address
+        let dev = i2c::LinuxI2c::new(
+            LinuxI2CBus::new(&args.bus).unwrap()
+        );
+        let name = args.name.first().cloned().unwrap_or_else(|| String::from("0"));
+        let calibration = calibration_for(&name, &saved, &args);
+        if calibrating {
+            let calibration = calibrate(&name, dev, sensors::DEFAULT_ADDRESS, calibration, &args).await;
+            saved.insert(name, calibration);
+            calibration::save(Path::new(args.calibration.as_ref().unwrap()), &saved)
+                .expect("Couldn't save the calibration");
+            return
+        }
+        run_sensor(name, dev, sensors::DEFAULT_ADDRESS, calibration, args.interrupt_line.first().copied(),
+                   Arc::clone(&args), events).await;
+        return
+    }
+    let sensors = sensors::bring_up(&args.bus, &args.xshut_chip, &args.xshut_line, &args.name, args.first_address)
+        .await
+        .expect("Couldn't bring up the sensors");
+    let mut tasks = Vec::with_capacity(sensors.len());
+    for (index, sensor) in sensors.iter().enumerate() {
+        let dev = i2c::LinuxI2c::with_address(
+            LinuxI2CBus::new(&args.bus).unwrap(),
+            sensor.address as u16,
+        );
+        let calibration = calibration_for(&sensor.name, &saved, &args);
+        if calibrating {
+            let calibration = calibrate(&sensor.name, dev, sensor.address, calibration, &args).await;
+            saved.insert(sensor.name.clone(), calibration);
+            continue
+        }
+        let interrupt_line = args.interrupt_line.get(index).copied();
+        tasks.push(tokio::spawn(run_sensor(sensor.name.clone(), dev, sensor.address, calibration,
+                                           interrupt_line, Arc::clone(&args), events.clone())));
+    }
+    if calibrating {
+        calibration::save(Path::new(args.calibration.as_ref().unwrap()), &saved)
+            .expect("Couldn't save the calibration");
+    }
+    for task in tasks {
+        task.await.unwrap();
     }
+    // keeps the XSHUT lines held until every sensor has stopped
+    drop(sensors);
 }
\ No newline at end of file
@@ -0,0 +1,107 @@
+use std::time::Duration;
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags, errors::Error as GpioError};
+use i2cdev::core::I2CDevice;
+use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
+use log::info;
+use thiserror;
+
+/// Address every VL53L4CD answers on after power up.
+pub const DEFAULT_ADDRESS: u8 = 0x29;
+/// I2C_SLAVE__DEVICE_ADDRESS, holding the 7-bit address the sensor answers on.
+const DEVICE_ADDRESS_REGISTER: [u8; 2] = [0x00, 0x01];
+/// First of the reserved 10-bit addressing prefixes, no 7-bit address may reach it.
+const RESERVED_ADDRESS: u8 = 0x78;
+/// Time the sensor needs to boot once XSHUT is released, with some margin.
+const BOOT_TIME: Duration = Duration::from_millis(10);
+
+/// One sensor of several sharing a bus, moved to its own address.
+pub struct Sensor {
+    pub name: String,
+    pub address: u8,
+    /// held high for as long as the sensor is in use, releasing it would leave XSHUT floating
+    _xshut: LineHandle,
+}
+
+/// Bring up one sensor per XSHUT line on `bus`. All are held in reset, then released one at a time
+/// and moved from the default address to `first_address`, `first_address + 1` and so on, so each
+/// answers on its own address. Sensors are named from `names`, or by index where none is given.
+pub async fn bring_up(bus: &str, chip: &str, xshut_lines: &[u32], names: &[String], first_address: u8)
+                      -> Result<Vec<Sensor>, Error> {
+    let last_address = first_address as usize + xshut_lines.len().saturating_sub(1);
+    if last_address >= RESERVED_ADDRESS as usize {
+        return Err(Error::AddressRange {first_address, sensors: xshut_lines.len()})
+    }
+    // a sensor moved to the default address would answer alongside the next one released, and
+    // both would take that one's new address. Only the last sensor can safely keep it.
+    if (first_address as usize..last_address).contains(&(DEFAULT_ADDRESS as usize)) {
+        return Err(Error::AddressDefault {first_address, sensors: xshut_lines.len()})
+    }
+    let mut chip_handle = Chip::new(chip).map_err(|e| Error::ChipError {source: e, chip: chip.to_string()})?;
+    let mut xshuts = Vec::with_capacity(xshut_lines.len());
+    for &line in xshut_lines {
+        let handle = chip_handle.get_line(line)
+            .map_err(|e| Error::LineGetError {source: e, line})?
+            .request(LineRequestFlags::OUTPUT, 0, "vl53l4cd_xshut")
+            .map_err(|e| Error::LineReqError {source: e, line})?;
+        xshuts.push((line, handle));
+    }
+    tokio::time::sleep(BOOT_TIME).await;
+
+    let mut sensors = Vec::with_capacity(xshuts.len());
+    for (index, (line, xshut)) in xshuts.into_iter().enumerate() {
+        let address = first_address + index as u8;
+        let name = names.get(index).cloned().unwrap_or_else(|| index.to_string());
+        xshut.set_value(1).map_err(|e| Error::LineSetError {source: e, line})?;
+        tokio::time::sleep(BOOT_TIME).await;
+        let mut device = LinuxI2CDevice::new(bus, DEFAULT_ADDRESS as u16)
+            .map_err(|e| Error::AddressError {source: e, sensor: name.clone()})?;
+        device.write(&[DEVICE_ADDRESS_REGISTER[0], DEVICE_ADDRESS_REGISTER[1], address])
+            .map_err(|e| Error::AddressError {source: e, sensor: name.clone()})?;
+        info!("Sensor {} on XSHUT line {} moved to address {:#04x}", name, line, address);
+        sensors.push(Sensor {
+            name,
+            address,
+            _xshut: xshut,
+        });
+    }
+    Ok(sensors)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed to get chip {chip}")]
+    ChipError {
+        source: GpioError,
+        chip: String,
+    },
+    #[error("Failed to get XSHUT line {line}")]
+    LineGetError {
+        source: GpioError,
+        line: u32,
+    },
+    #[error("Failed to request XSHUT line {line}")]
+    LineReqError {
+        source: GpioError,
+        line: u32,
+    },
+    #[error("Failed to set XSHUT line {line}")]
+    LineSetError {
+        source: GpioError,
+        line: u32,
+    },
+    #[error("{sensors} sensors starting at address {first_address:#04x} run past the last 7-bit address 0x77")]
+    AddressRange {
+        first_address: u8,
+        sensors: usize,
+    },
+    #[error("{sensors} sensors starting at address {first_address:#04x} give the default address 0x29 to a sensor other than the last")]
+    AddressDefault {
+        first_address: u8,
+        sensors: usize,
+    },
+    #[error("Failed to set the address of sensor {sensor}")]
+    AddressError {
+        source: LinuxI2CError,
+        sensor: String,
+    },
+}
\ No newline at end of file
@@ -0,0 +1,131 @@
+use std::time::Instant;
+use vl53l4cd::Measurement;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// something came closer than the threshold
+    Entered { distance: u16, at: Instant },
+    /// the path cleared past the threshold plus hysteresis
+    Exited { distance: u16, at: Instant },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TripwireConfig {
+    /// distance in mm below which the tripwire is broken
+    pub threshold: u16,
+    /// extra distance in mm needed to clear it again, so readings near the threshold don't flicker
+    pub hysteresis: u16,
+    /// consecutive valid readings needed on the other side before an event is reported
+    pub dwell: usize,
+}
+
+/// Turns a stream of distance readings into entered and exited events.
+pub struct Tripwire {
+    config: TripwireConfig,
+    occupied: bool,
+    streak: usize,
+}
+
+impl Tripwire {
+    pub fn new(config: TripwireConfig) -> Self {
+        Tripwire {
+            config,
+            occupied: false,
+            streak: 0,
+        }
+    }
+
+    /// Feed a measurement, skipping it if the sensor flagged it invalid.
+    pub fn feed(&mut self, measure: &Measurement) -> Option<Event> {
+        if !measure.is_valid() {
+            return None
+        }
+        self.update(measure.distance)
+    }
+
+    /// Feed a distance in mm, returning an event when the state changes.
+    pub fn update(&mut self, distance: u16) -> Option<Event> {
+        let crossed = if self.occupied {
+            distance as u32 > self.config.threshold as u32 + self.config.hysteresis as u32
+        } else {
+            distance < self.config.threshold
+        };
+        if !crossed {
+            self.streak = 0;
+            return None
+        }
+        self.streak += 1;
+        if self.streak < self.config.dwell.max(1) {
+            return None
+        }
+        self.streak = 0;
+        self.occupied = !self.occupied;
+        let at = Instant::now();
+        Some(if self.occupied {
+            Event::Entered {distance, at}
+        } else {
+            Event::Exited {distance, at}
+        })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tripwire(dwell: usize) -> Tripwire {
+        Tripwire::new(TripwireConfig {
+            threshold: 100,
+            hysteresis: 20,
+            dwell,
+        })
+    }
+
+    /// Feed every distance, returning the index and kind of each event.
+    fn events(tripwire: &mut Tripwire, distances: &[u16]) -> Vec<(usize, &'static str)> {
+        distances.iter()
+            .enumerate()
+            .filter_map(|(index, &distance)| match tripwire.update(distance)? {
+                Event::Entered {..} => Some((index, "entered")),
+                Event::Exited {..} => Some((index, "exited")),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn waits_for_dwell() {
+        let mut tripwire = tripwire(3);
+        assert_eq!(events(&mut tripwire, &[90, 90]), []);
+        assert_eq!(events(&mut tripwire, &[90]), [(0, "entered")]);
+        // still inside, no repeat
+        assert_eq!(events(&mut tripwire, &[50, 50, 50]), []);
+    }
+
+    #[test]
+    fn streak_resets_across_threshold() {
+        let mut tripwire = tripwire(3);
+        assert_eq!(events(&mut tripwire, &[90, 90, 150, 90, 90]), []);
+        assert_eq!(events(&mut tripwire, &[90]), [(0, "entered")]);
+        assert_eq!(events(&mut tripwire, &[200, 200, 50, 200, 200]), []);
+        assert_eq!(events(&mut tripwire, &[200]), [(0, "exited")]);
+    }
+
+    #[test]
+    fn exits_past_hysteresis() {
+        let mut tripwire = tripwire(1);
+        assert_eq!(events(&mut tripwire, &[99]), [(0, "entered")]);
+        // back over the threshold but within the hysteresis band
+        assert_eq!(events(&mut tripwire, &[100, 110, 120, 120]), []);
+        assert_eq!(events(&mut tripwire, &[121]), [(0, "exited")]);
+        // entering again needs to be under the threshold itself
+        assert_eq!(events(&mut tripwire, &[100, 99]), [(1, "entered")]);
+    }
+
+    #[test]
+    fn zero_dwell_is_one() {
+        let mut zero = tripwire(0);
+        let mut one = tripwire(1);
+        let distances = [150, 90, 90, 110, 130, 80, 200];
+        assert_eq!(events(&mut zero, &distances), events(&mut one, &distances));
+        assert_eq!(events(&mut tripwire(0), &distances), [(1, "entered"), (4, "exited"), (5, "entered"), (6, "exited")]);
+    }
+}
\ No newline at end of file